@@ -1,16 +1,191 @@
 pub use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 
+use core::mem::offset_of;
+
+/// The legacy region saved by `fxsave`, holding the x87, MMX and SSE state.
+#[cfg(target_feature = "sse")]
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Debug)]
+pub struct FxsaveArea {
+    pub fcw: u16,
+    pub fsw: u16,
+    /// The abridged tag word: one bit per register, set if it is valid
+    pub ftw: u8,
+    _reserved0: u8,
+    pub fop: u16,
+    pub fip: u64,
+    pub fdp: u64,
+    pub mxcsr: u32,
+    pub mxcsr_mask: u32,
+    /// `ST0`-`ST7`/`MM0`-`MM7`, 80 bits of each 16 byte slot are used
+    pub st: [[u8; 16]; 8],
+    pub xmm: [u128; 16],
+    _reserved1: [u8; 96],
+}
+
+/// The register state of the code an interrupt or exception interrupted, as saved by the
+/// handlers [`interrupt_handler`] and [`exception_handler`] build.
+///
+/// This is built on the interrupt stack directly below the frame the CPU pushes, so its layout is
+/// fixed and part of the ABI between the loader and modules: fields are never reordered or
+/// resized, from the lowest address up:
+///
+/// | Offset (SSE) | Offset (no SSE) | Field |
+/// |--------------|-----------------|-------|
+/// | 0            | -               | `fxsave` (512 bytes) |
+/// | 512          | 0               | reserved (8 bytes) |
+/// | 520          | 8               | `r15`, `r14`, ..., `r8`, `rbp`, `rdi`, `rsi`, ..., `rbx`, `rax` |
+/// | 640          | 128             | `error_code` |
+/// | 648          | 136             | `rip`, `cs`, `rflags`, `rsp`, `ss`, as pushed by the CPU |
+///
+/// Changes a handler makes to the registers are loaded when it returns, including the `rip`,
+/// `rsp` and `rflags` that `iretq` resumes with.
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptContext {
+    #[cfg(target_feature = "sse")]
+    pub fxsave: FxsaveArea,
+    // Keeps `fxsave` 16 byte aligned, as the CPU aligns the stack before pushing its frame
+    _reserved: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// The error code pushed by the CPU, or `0` for vectors without one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+#[cfg(target_feature = "sse")]
+const _: () = assert!(core::mem::size_of::<InterruptContext>() == 688);
+#[cfg(not(target_feature = "sse"))]
+const _: () = assert!(core::mem::size_of::<InterruptContext>() == 176);
+
+/// Space below the general purpose registers: the reserved word and, with SSE, the FXSAVE area
+const SCRATCH_SIZE: usize = offset_of!(InterruptContext, r15);
+const ERROR_CODE_OFFSET: usize = offset_of!(InterruptContext, error_code);
+
+/// Pushes the general purpose registers and makes room for the rest of an [`InterruptContext`],
+/// once its error code has been pushed, then saves the extended state into it.
 #[cfg(target_feature = "sse")]
-#[repr(align(16))]
-pub struct InterruptContext {}
+macro_rules! save_context {
+    () => {
+        concat!(
+            "push rax\n",
+            "push rbx\n",
+            "push rcx\n",
+            "push rdx\n",
+            "push rsi\n",
+            "push rdi\n",
+            "push rbp\n",
+            "push r8\n",
+            "push r9\n",
+            "push r10\n",
+            "push r11\n",
+            "push r12\n",
+            "push r13\n",
+            "push r14\n",
+            "push r15\n",
+            "sub rsp, {scratch}\n",
+            "fxsave [rsp]\n",
+            "cld\n",
+        )
+    };
+}
 
 #[cfg(not(target_feature = "sse"))]
-pub struct InterruptContext<'a> {
-    pub frame: &'a mut InterruptStackFrame,
-    pub gprs: [usize; 15],
+macro_rules! save_context {
+    () => {
+        concat!(
+            "push rax\n",
+            "push rbx\n",
+            "push rcx\n",
+            "push rdx\n",
+            "push rsi\n",
+            "push rdi\n",
+            "push rbp\n",
+            "push r8\n",
+            "push r9\n",
+            "push r10\n",
+            "push r11\n",
+            "push r12\n",
+            "push r13\n",
+            "push r14\n",
+            "push r15\n",
+            "sub rsp, {scratch}\n",
+            "cld\n",
+        )
+    };
+}
+
+/// Reverses [`save_context!`], dropping the error code, and returns from the interrupt.
+#[cfg(target_feature = "sse")]
+macro_rules! restore_context {
+    () => {
+        concat!(
+            "fxrstor [rsp]\n",
+            "add rsp, {scratch}\n",
+            "pop r15\n",
+            "pop r14\n",
+            "pop r13\n",
+            "pop r12\n",
+            "pop r11\n",
+            "pop r10\n",
+            "pop r9\n",
+            "pop r8\n",
+            "pop rbp\n",
+            "pop rdi\n",
+            "pop rsi\n",
+            "pop rdx\n",
+            "pop rcx\n",
+            "pop rbx\n",
+            "pop rax\n",
+            "add rsp, 8\n",
+            "iretq\n",
+        )
+    };
 }
 
-use crate::hcf;
+#[cfg(not(target_feature = "sse"))]
+macro_rules! restore_context {
+    () => {
+        concat!(
+            "add rsp, {scratch}\n",
+            "pop r15\n",
+            "pop r14\n",
+            "pop r13\n",
+            "pop r12\n",
+            "pop r11\n",
+            "pop r10\n",
+            "pop r9\n",
+            "pop r8\n",
+            "pop rbp\n",
+            "pop rdi\n",
+            "pop rsi\n",
+            "pop rdx\n",
+            "pop rcx\n",
+            "pop rbx\n",
+            "pop rax\n",
+            "add rsp, 8\n",
+            "iretq\n",
+        )
+    };
+}
 
 pub unsafe trait InterruptResult {
     const IS_DIVERGING: bool = false;
@@ -27,8 +202,10 @@ unsafe impl InterruptErrorCode for u64 {}
 unsafe impl InterruptErrorCode for usize {}
 unsafe impl InterruptErrorCode for PageFaultErrorCode {}
 
+/// Wraps `f` in a handler for a vector the CPU pushes an error code for. `f` is passed the
+/// interrupted [`InterruptContext`] and the error code.
 pub const fn exception_handler<
-    F: Fn(&mut InterruptStackFrame, T) -> R,
+    F: Fn(&mut InterruptContext, T) -> R,
     T: InterruptErrorCode,
     R: InterruptResult,
 >(
@@ -44,42 +221,21 @@ pub const fn exception_handler<
     core::mem::forget(f);
 
     extern "C" fn call_hdl<
-        F: FnOnce(&mut InterruptStackFrame, T) -> R,
+        F: Fn(&mut InterruptContext, T) -> R,
         R: InterruptResult,
         T: InterruptErrorCode,
     >(
-        x: &mut InterruptStackFrame,
+        ctx: &mut InterruptContext,
         errc: T,
     ) -> R {
         let f = unsafe { core::mem::conjure_zst::<F>() };
-        f(x, errc)
+        f(ctx, errc)
     }
 
-    #[unsafe(naked)]
-
-    extern "x86-interrupt" fn hdl_impl_diverging<
-        F: FnOnce(&mut InterruptStackFrame, T) -> R,
-        R: InterruptResult,
-        T: InterruptErrorCode,
-    >(
-        sframe: InterruptStackFrame,
-        errc: T,
-    ) -> ! {
-        core::arch::naked_asm! {
-            "pop rsi",
-            "lea rdi, [rsp]",
-            "and rsp, ~15",
-            "call {bounce}",
-            "jmp {hcf}",
-            bounce = sym call_hdl::<F, R, T>,
-            hcf = sym hcf,
-        }
-    }
-
-    #[cfg(target_feature = "sse")]
+    // A diverging `f` simply never returns to the restore sequence
     #[unsafe(naked)]
     extern "x86-interrupt" fn hdl_impl<
-        F: FnOnce(&mut InterruptStackFrame, T) -> R,
+        F: Fn(&mut InterruptContext, T) -> R,
         R: InterruptResult,
         T: InterruptErrorCode,
     >(
@@ -87,92 +243,27 @@ pub const fn exception_handler<
         errc: T,
     ) {
         core::arch::naked_asm! {
-            "xchg [rsp], rax",
-            "push rbp",
-            "lea rbp, [rsp]",
-            "and rsp, ~15",
-            "push rsi",
-            "mov esi, eax",
-            "push rdi",
-            "push rdx",
-            "push rcx",
-            "push r8",
-            "push r9",
-            "push r10",
-            "push r11",
-            "sub rsp, 464",
-            "fxsave [rsp]",
-            "fninit",
-            "lea rdi, [rbp+16]",
+            save_context!(),
+            "mov rdi, rsp",
+            "mov rsi, [rsp + {errc}]",
             "call {bounce}",
-            "fxrstor [rsp]",
-            "add rsp, 464",
-            "pop r11",
-            "pop r10",
-            "pop r9",
-            "pop r8",
-            "pop rcx",
-            "pop rdx",
-            "pop rdi",
-            "pop rsi",
-            "leave",
-            "pop rax",
-            "iretq",
+            restore_context!(),
+            scratch = const SCRATCH_SIZE,
+            errc = const ERROR_CODE_OFFSET,
             bounce = sym call_hdl::<F, R, T>,
         }
     }
 
-    #[cfg(not(target_feature = "sse"))]
-    #[unsafe(naked)]
-    extern "x86-interrupt" fn hdl_impl<F: Fn(&mut InterruptStackFrame) -> R, R: InterruptResult>(
-        sframe: InterruptStackFrame,
-    ) {
-        core::arch::naked_asm!(
-            "push rbp",
-            "mov rbp, rsp",
-            "and rsp, ~15",
-            "push rdi",
-            "push rsi",
-            "push rdx",
-            "push rcx",
-            "push rax",
-            "push r8",
-            "push r9",
-            "push r10",
-            "push r11",
-            "lea rdi, [rbp+8]",
-            "call {bounce}",
-            "pop r11",
-            "pop r10",
-            "pop r9",
-            "pop r8",
-            "pop rax",
-            "pop rcx",
-            "pop rdx",
-            "pop rsi",
-            "pop rdi",
-            "leave",
-            "iretq",
+    unsafe {
+        core::mem::transmute(
+            hdl_impl::<F, R, T> as extern "x86-interrupt" fn(InterruptStackFrame, T),
         )
     }
-
-    if const { R::IS_DIVERGING } {
-        unsafe {
-            core::mem::transmute(
-                hdl_impl::<F, R, T> as extern "x86-interrupt" fn(InterruptStackFrame, T),
-            )
-        }
-    } else {
-        unsafe {
-            core::mem::transmute(
-                hdl_impl_diverging::<F, R, T>
-                    as extern "x86-interrupt" fn(InterruptStackFrame, T) -> !,
-            )
-        }
-    }
 }
 
-pub const fn interrupt_handler<F: Fn(&mut InterruptStackFrame) -> R, R: InterruptResult>(
+/// Wraps `f` in a handler for a vector without an error code. `f` is passed the interrupted
+/// [`InterruptContext`], whose `error_code` is `0`.
+pub const fn interrupt_handler<F: Fn(&mut InterruptContext) -> R, R: InterruptResult>(
     f: F,
 ) -> extern "x86-interrupt" fn(InterruptStackFrame) -> R {
     const {
@@ -184,110 +275,29 @@ pub const fn interrupt_handler<F: Fn(&mut InterruptStackFrame) -> R, R: Interrup
 
     core::mem::forget(f);
 
-    extern "C" fn call_hdl<F: Fn(&mut InterruptStackFrame) -> R, R: InterruptResult>(
-        x: &mut InterruptStackFrame,
+    extern "C" fn call_hdl<F: Fn(&mut InterruptContext) -> R, R: InterruptResult>(
+        ctx: &mut InterruptContext,
     ) -> R {
         let f = unsafe { core::mem::conjure_zst::<F>() };
-        f(x)
+        f(ctx)
     }
 
     #[unsafe(naked)]
-    extern "x86-interrupt" fn hdl_impl_diverging<
-        F: Fn(&mut InterruptStackFrame) -> R,
-        R: InterruptResult,
-    >(
-        sframe: InterruptStackFrame,
-    ) -> ! {
-        core::arch::naked_asm! {
-            "lea rdi, [rsp]",
-            "and rsp, ~15",
-            "call {bounce}",
-            "jmp {hcf}",
-            bounce = sym call_hdl::<F, R>,
-            hcf = sym hcf,
-        }
-    }
-
-    #[unsafe(naked)]
-    #[cfg(target_feature = "sse")]
-    extern "x86-interrupt" fn hdl_impl<F: Fn(&mut InterruptStackFrame) -> R, R: InterruptResult>(
+    extern "x86-interrupt" fn hdl_impl<F: Fn(&mut InterruptContext) -> R, R: InterruptResult>(
         sframe: InterruptStackFrame,
     ) {
         core::arch::naked_asm! {
-            "push rbp",
-            "mov rbp, rsp",
-            "and rsp, ~15",
-            "push rdi",
-            "push rsi",
-            "push rdx",
-            "push rcx",
-            "push r8",
-            "push r9",
-            "push r10",
-            "push r11",
-            "sub rsp, 464",
-            "fxsave [rsp]",
-            "lea rdi, [rbp+8]",
+            "push 0",
+            save_context!(),
+            "mov rdi, rsp",
             "call {bounce}",
-            "fxrstor [rsp]",
-            "add rsp, 464",
-            "pop r11",
-            "pop r10",
-            "pop r9",
-            "pop r8",
-            "pop rcx",
-            "pop rdx",
-            "pop rsi",
-            "pop rdi",
-            "leave",
-            "iretq",
+            restore_context!(),
+            scratch = const SCRATCH_SIZE,
             bounce = sym call_hdl::<F, R>,
         }
     }
 
-    #[cfg(not(target_feature = "sse"))]
-    #[unsafe(naked)]
-    extern "x86-interrupt" fn hdl_impl<F: Fn(&mut InterruptStackFrame) -> R, R: InterruptResult>(
-        sframe: InterruptStackFrame,
-    ) {
-        core::arch::naked_asm!(
-            "push rbp",
-            "mov rbp, rsp",
-            "and rsp, ~15",
-            "push rdi",
-            "push rsi",
-            "push rdx",
-            "push rcx",
-            "push rax",
-            "push r8",
-            "push r9",
-            "push r10",
-            "push r11",
-            "lea rdi, [rbp+8]",
-            "call {bounce}",
-            "pop r11",
-            "pop r10",
-            "pop r9",
-            "pop r8",
-            "pop rax",
-            "pop rcx",
-            "pop rdx",
-            "pop rsi",
-            "pop rdi",
-            "leave",
-            "iretq",
-        )
-    }
-
-    if const { R::IS_DIVERGING } {
-        unsafe {
-            core::mem::transmute(hdl_impl::<F, R> as extern "x86-interrupt" fn(InterruptStackFrame))
-        }
-    } else {
-        unsafe {
-            core::mem::transmute(
-                hdl_impl_diverging::<F, R> as extern "x86-interrupt" fn(InterruptStackFrame) -> !,
-            )
-        }
+    unsafe {
+        core::mem::transmute(hdl_impl::<F, R> as extern "x86-interrupt" fn(InterruptStackFrame))
     }
 }
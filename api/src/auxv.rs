@@ -1,10 +1,111 @@
 pub use lilium_sys::sys::auxv::*;
 
-/// [`AuxEnt::a_value`] poinrs to
+/// The auxiliary vector value is a pointer to a [`GptBootPartition`] describing the partition
+/// the system was booted from
 pub const AT_LILIUM_OS_BOOT_PART_GPT: usize = 96;
+/// The auxiliary vector value is a pointer to an [`MbrBootPartition`] describing the partition
+/// the system was booted from
 pub const AT_LILIUM_OS_BOOT_PART_MBR: usize = 97;
 
+/// The auxiliary vector value is a pointer to a [`MemoryMap`] of physical memory
+pub const AT_LILIUM_OS_MEMORY_MAP: usize = 98;
+/// The auxiliary vector value is a pointer to a [`FramebufferInfo`] describing the boot
+/// framebuffer
+pub const AT_LILIUM_OS_FRAMEBUFFER: usize = 99;
+/// The physical address of the ACPI RSDP, if the firmware provides one
+pub const AT_LILIUM_OS_RSDP: usize = 100;
+/// The offset at which all of physical memory is mapped (the higher-half direct map)
+pub const AT_LILIUM_OS_HHDM_OFFSET: usize = 101;
+
+/// The address of the GDT the loader installed
 #[cfg(target_arch = "x86_64")]
 pub const AT_LILIUM_OS_GDT_BASE: usize = 108;
+/// The address of the IDT the loader installed
 #[cfg(target_arch = "x86_64")]
 pub const AT_LILIUM_OS_IDT_BASE: usize = 109;
+
+/// The boot partition on a GPT disk. GUIDs are in their on-disk (mixed-endian) byte order.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GptBootPartition {
+    pub disk_guid: [u8; 16],
+    pub partition_guid: [u8; 16],
+    /// 1-based index of the partition in the partition table
+    pub partition_index: u32,
+}
+
+/// The boot partition on an MBR disk.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MbrBootPartition {
+    /// The disk signature from the MBR
+    pub disk_id: u32,
+    /// 1-based index of the partition in the partition table
+    pub partition_index: u32,
+}
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct MemoryKind {
+        /// RAM owned by the loader's frame allocator, including what the loader already uses from
+        /// it: its heap, page tables, module images and the kernel's stack. The kernel must not
+        /// use it directly, only through the heap and the [`crate::mem`] calls.
+        Usable = 0,
+        Reserved = 1,
+        AcpiReclaimable = 2,
+        AcpiNvs = 3,
+        BadMemory = 4,
+        /// Used by the bootloader. The loader has already reclaimed it by the time the kernel
+        /// starts, so it is managed by the loader's frame allocator like [`Self::Usable`] memory.
+        BootloaderReclaimable = 5,
+        /// The loader and module images
+        ExecutableAndModules = 6,
+        Framebuffer = 7,
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    pub base: u64,
+    pub length: u64,
+    pub kind: MemoryKind,
+}
+
+/// The physical memory map, sorted by base address. Like everything else the auxiliary vector
+/// points to, the entries are stored on the kernel's initial stack.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMap {
+    pub entries: *const MemoryMapEntry,
+    pub len: usize,
+}
+
+impl MemoryMap {
+    /// # Safety
+    /// `self` must have been provided by the loader through [`AT_LILIUM_OS_MEMORY_MAP`].
+    pub unsafe fn entries(&self) -> &[MemoryMapEntry] {
+        unsafe { core::slice::from_raw_parts(self.entries, self.len) }
+    }
+}
+
+/// Geometry and pixel format of a linear framebuffer. Pixels are `bpp` bits wide, with each color
+/// channel occupying `size` bits starting at bit `shift`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FramebufferInfo {
+    /// Virtual address of the framebuffer, in the higher-half direct map
+    pub address: *mut u8,
+    pub phys_address: u64,
+    pub width: u64,
+    pub height: u64,
+    /// Bytes per row
+    pub pitch: u64,
+    pub bpp: u16,
+    pub red_mask_size: u8,
+    pub red_mask_shift: u8,
+    pub green_mask_size: u8,
+    pub green_mask_shift: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_shift: u8,
+}
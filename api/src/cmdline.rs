@@ -0,0 +1,403 @@
+//! Parsing of the kernel command line (`kernel_cmdline` in `limine.conf`).
+//!
+//! The command line is split on whitespace. `--name value`, `--name=value` and `name=value` all
+//! set the option `name`, and a bare `--name` sets a boolean option. Anything else, and everything
+//! after a lone `--`, is a positional argument. When an option is given more than once, the last
+//! occurrence wins.
+//!
+//! The loader and modules declare the options they understand with
+//! [`cmdline_options!`](crate::cmdline_options). The loader reads the declarations of every module
+//! out of its file, so that it can warn about options nothing declared.
+
+use core::{
+    ffi::CStr,
+    fmt,
+    str::{FromStr, SplitWhitespace},
+};
+
+use crate::module::{fixed_str, unfixed_str};
+
+/// Name of the section [`cmdline_options!`](crate::cmdline_options) places declarations in
+pub const CMDLINE_SECTION: &CStr = c".lilium.cmdline";
+
+pub const OPTION_NAME_LEN: usize = 32;
+pub const OPTION_HELP_LEN: usize = 128;
+pub const OPTION_CHOICES_LEN: usize = 64;
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct OptionKind {
+        /// Given as a bare `--name`, or with a value like `yes` or `off`
+        Bool = 0,
+        /// A decimal or `0x`-prefixed hexadecimal integer
+        Int = 1,
+        /// Any string
+        Str = 2,
+        /// One of a fixed set of strings
+        Enum = 3,
+    }
+}
+
+/// Declaration of a command line option. Stored inline, like
+/// [`ModInfo`](crate::module::ModInfo), so the loader can read it out of module files.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct OptionSpec {
+    pub name: [u8; OPTION_NAME_LEN],
+    pub kind: OptionKind,
+    pub help: [u8; OPTION_HELP_LEN],
+    /// The values of an [`OptionKind::Enum`] option, separated by `|`
+    pub choices: [u8; OPTION_CHOICES_LEN],
+}
+
+impl OptionSpec {
+    const fn new(name: &str, kind: OptionKind, choices: &str, help: &str) -> Self {
+        Self {
+            name: fixed_str(name),
+            kind,
+            help: fixed_str(help),
+            choices: fixed_str(choices),
+        }
+    }
+
+    pub const fn bool(name: &str, help: &str) -> Self {
+        Self::new(name, OptionKind::Bool, "", help)
+    }
+
+    pub const fn int(name: &str, help: &str) -> Self {
+        Self::new(name, OptionKind::Int, "", help)
+    }
+
+    pub const fn str(name: &str, help: &str) -> Self {
+        Self::new(name, OptionKind::Str, "", help)
+    }
+
+    /// An option taking one of `choices`, separated by `|`, such as `"enforce|warn"`.
+    pub const fn choice(name: &str, choices: &str, help: &str) -> Self {
+        Self::new(name, OptionKind::Enum, choices, help)
+    }
+
+    pub fn name(&self) -> &str {
+        unfixed_str(&self.name).to_str().unwrap_or("")
+    }
+
+    pub fn help(&self) -> &str {
+        unfixed_str(&self.help).to_str().unwrap_or("")
+    }
+
+    pub fn choices(&self) -> impl Iterator<Item = &str> {
+        unfixed_str(&self.choices)
+            .to_str()
+            .unwrap_or("")
+            .split('|')
+            .filter(|choice| !choice.is_empty())
+    }
+}
+
+impl fmt::Display for OptionSpec {
+    /// Formats the option as a line of help text.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "--{}", self.name())?;
+        match self.kind {
+            OptionKind::Int => f.write_str(" <int>")?,
+            OptionKind::Str => f.write_str(" <string>")?,
+            OptionKind::Enum => {
+                f.write_str(" ")?;
+                for (i, choice) in self.choices().enumerate() {
+                    if i != 0 {
+                        f.write_str("|")?;
+                    }
+                    f.write_str(choice)?;
+                }
+            }
+            _ => {}
+        }
+        write!(f, ": {}", self.help())
+    }
+}
+
+/// Declares the command line options the current module (or the loader) understands. Use it at
+/// most once per module.
+///
+/// ```ignore
+/// los_api::cmdline_options! {
+///     static KERNEL_OPTIONS = [
+///         OptionSpec::str("init", "Path of the first program to run"),
+///         OptionSpec::bool("quiet", "Print less while booting"),
+///     ];
+/// }
+/// ```
+#[macro_export]
+macro_rules! cmdline_options {
+    ($vis:vis static $name:ident = [$($spec:expr),* $(,)?];) => {
+        // Exported under a name of its own, after the crate, rather than whatever name the
+        // caller picked, which other modules could pick too
+        #[used]
+        #[unsafe(export_name = concat!("__lilium_cmdline_", env!("CARGO_CRATE_NAME")))]
+        #[unsafe(link_section = ".lilium.cmdline")]
+        $vis static $name: [$crate::cmdline::OptionSpec; [$(stringify!($spec)),*].len()] = {
+            use $crate::cmdline::OptionSpec;
+            [$($spec),*]
+        };
+    };
+}
+
+/// Parses a boolean value: `1`, `true`, `yes` or `on`, or `0`, `false`, `no` or `off`.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    if ["1", "true", "yes", "on"]
+        .iter()
+        .any(|s| value.eq_ignore_ascii_case(s))
+    {
+        Some(true)
+    } else if ["0", "false", "no", "off"]
+        .iter()
+        .any(|s| value.eq_ignore_ascii_case(s))
+    {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Parses a decimal integer, or a hexadecimal one prefixed with `0x`.
+pub fn parse_int<T: FromStr + FromStrRadix>(value: &str) -> Option<T> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => T::from_str_radix(hex, 16),
+        None => value.parse().ok(),
+    }
+}
+
+/// The integer types [`parse_int`] supports.
+pub trait FromStrRadix: Sized {
+    fn from_str_radix(s: &str, radix: u32) -> Option<Self>;
+}
+
+macro_rules! from_str_radix {
+    ($($ty:ty),*) => {
+        $(
+            impl FromStrRadix for $ty {
+                fn from_str_radix(s: &str, radix: u32) -> Option<Self> {
+                    <$ty>::from_str_radix(s, radix).ok()
+                }
+            }
+        )*
+    };
+}
+
+from_str_radix!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token<'a> {
+    Option {
+        name: &'a str,
+        value: Option<&'a str>,
+    },
+    Positional(&'a str),
+}
+
+/// Splits a command line into [`Token`]s. `--name value` consumes the next word as the value of
+/// `name` if the specs say the option takes a value. Options the specs don't declare take the next
+/// word if it doesn't look like an option itself.
+#[derive(Clone)]
+pub struct Tokens<'a, A> {
+    args: A,
+    specs: &'a [OptionSpec],
+    options_done: bool,
+}
+
+impl<'a, A: Iterator<Item = &'a str> + Clone> Iterator for Tokens<'a, A> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let arg = self.args.next()?;
+        if self.options_done {
+            return Some(Token::Positional(arg));
+        }
+        if arg == "--" {
+            self.options_done = true;
+            return self.next();
+        }
+
+        if let Some(option) = arg.strip_prefix("--") {
+            if let Some((name, value)) = option.split_once('=') {
+                return Some(Token::Option {
+                    name,
+                    value: Some(value),
+                });
+            }
+            let takes_value = match self.specs.iter().find(|spec| spec.name() == option) {
+                Some(spec) => spec.kind != OptionKind::Bool,
+                None => self
+                    .args
+                    .clone()
+                    .next()
+                    .is_some_and(|next| !next.starts_with("--") && !next.contains('=')),
+            };
+            let value = if takes_value { self.args.next() } else { None };
+            return Some(Token::Option {
+                name: option,
+                value,
+            });
+        }
+
+        match arg.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Some(Token::Option {
+                name,
+                value: Some(value),
+            }),
+            _ => Some(Token::Positional(arg)),
+        }
+    }
+}
+
+/// The value of an option, checked against its [`OptionSpec`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value<'a> {
+    Bool(bool),
+    Int(i64),
+    Str(&'a str),
+    Enum(&'a str),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmdlineError<'a> {
+    /// No spec declares the option
+    Unknown(&'a str),
+    /// The option needs a value, but wasn't given one
+    MissingValue(&'a str),
+    /// The value doesn't parse as the option's kind, or isn't one of its choices
+    InvalidValue { name: &'a str, value: &'a str },
+}
+
+impl fmt::Display for CmdlineError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(f, "unknown option --{name}"),
+            Self::MissingValue(name) => write!(f, "option --{name} needs a value"),
+            Self::InvalidValue { name, value } => {
+                write!(f, "invalid value {value:?} for option --{name}")
+            }
+        }
+    }
+}
+
+fn check_value<'a>(
+    spec: &OptionSpec,
+    name: &'a str,
+    value: Option<&'a str>,
+) -> Result<Value<'a>, CmdlineError<'a>> {
+    let invalid = |value| CmdlineError::InvalidValue { name, value };
+    match (spec.kind, value) {
+        (OptionKind::Bool, None) => Ok(Value::Bool(true)),
+        (OptionKind::Bool, Some(value)) => parse_bool(value)
+            .map(Value::Bool)
+            .ok_or_else(|| invalid(value)),
+        (_, None) => Err(CmdlineError::MissingValue(name)),
+        (OptionKind::Int, Some(value)) => parse_int(value)
+            .map(Value::Int)
+            .ok_or_else(|| invalid(value)),
+        (OptionKind::Enum, Some(value)) => {
+            if spec.choices().any(|choice| choice == value) {
+                Ok(Value::Enum(value))
+            } else {
+                Err(invalid(value))
+            }
+        }
+        (_, Some(value)) => Ok(Value::Str(value)),
+    }
+}
+
+/// A command line, interpreted according to a set of [`OptionSpec`]s.
+///
+/// The specs only need to cover the options being looked up, except for [`Self::errors`], which
+/// reports every option the specs don't declare.
+#[derive(Clone)]
+pub struct CommandLine<'a, A = SplitWhitespace<'a>> {
+    args: A,
+    specs: &'a [OptionSpec],
+}
+
+impl<'a> CommandLine<'a> {
+    pub fn new(cmdline: &'a str, specs: &'a [OptionSpec]) -> Self {
+        Self::from_args(cmdline.split_whitespace(), specs)
+    }
+}
+
+impl<'a, A: Iterator<Item = &'a str> + Clone> CommandLine<'a, A> {
+    /// Interprets a command line that has already been split into words, such as `argv`.
+    pub fn from_args(args: A, specs: &'a [OptionSpec]) -> Self {
+        Self { args, specs }
+    }
+
+    pub fn tokens(&self) -> Tokens<'a, A> {
+        Tokens {
+            args: self.args.clone(),
+            specs: self.specs,
+            options_done: false,
+        }
+    }
+
+    fn spec(&self, name: &str) -> Option<&'a OptionSpec> {
+        self.specs.iter().find(|spec| spec.name() == name)
+    }
+
+    /// The value of the last occurrence of the option `name`, which must be declared by the specs.
+    pub fn get(&self, name: &str) -> Option<Result<Value<'a>, CmdlineError<'a>>> {
+        let spec = self.spec(name)?;
+        self.tokens()
+            .filter_map(|token| match token {
+                Token::Option { name: n, value } if n == name => Some((n, value)),
+                _ => None,
+            })
+            .last()
+            .map(|(name, value)| check_value(spec, name, value))
+    }
+
+    /// Whether the boolean option `name` is set. Invalid values count as unset.
+    pub fn flag(&self, name: &str) -> bool {
+        matches!(self.get(name), Some(Ok(Value::Bool(true))))
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        match self.get(name)? {
+            Ok(Value::Int(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value of a string or enum option, if it is given and valid.
+    pub fn string(&self, name: &str) -> Option<&'a str> {
+        match self.get(name)? {
+            Ok(Value::Str(value) | Value::Enum(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn positional(&self) -> impl Iterator<Item = &'a str> {
+        self.tokens().filter_map(|token| match token {
+            Token::Positional(arg) => Some(arg),
+            Token::Option { .. } => None,
+        })
+    }
+
+    /// Every occurrence of an undeclared option or an invalid value.
+    pub fn errors(&self) -> impl Iterator<Item = CmdlineError<'a>> {
+        self.tokens().filter_map(|token| match token {
+            Token::Option { name, value } => match self.spec(name) {
+                Some(spec) => check_value(spec, name, value).err(),
+                None => Some(CmdlineError::Unknown(name)),
+            },
+            Token::Positional(_) => None,
+        })
+    }
+
+    /// Prints a warning for each of [`Self::errors`].
+    pub fn warn(&self) {
+        for error in self.errors() {
+            crate::println!("warning: kernel command line: {error}");
+        }
+    }
+}
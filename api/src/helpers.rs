@@ -1,2 +1,26 @@
 #[repr(C, align(16))]
 pub struct Align16<T>(pub T);
+
+/// Gives a `fake_enum` error `from_status` and `into_status`, to pass it across the module
+/// boundary as a status code where `0` means success.
+macro_rules! status_error {
+    ($ty:ident) => {
+        impl $ty {
+            /// Converts a status code returned across the module boundary, where `0` means success.
+            pub fn from_status(status: u32) -> Result<(), Self> {
+                match status {
+                    0 => Ok(()),
+                    x => Err(Self(x)),
+                }
+            }
+
+            pub fn into_status(res: Result<(), Self>) -> u32 {
+                match res {
+                    Ok(()) => 0,
+                    Err(e) => e.0,
+                }
+            }
+        }
+    };
+}
+pub(crate) use status_error;
@@ -0,0 +1,86 @@
+//! One-shot events on the comparators of the High Precision Event Timer.
+//!
+//! Each comparator interrupts once the HPET's main counter reaches the deadline it is armed with,
+//! independently of the kernel tick (see [`crate::timer`]). Deadlines are in main counter ticks,
+//! which run at [`frequency`] per second.
+
+use core::ffi::c_void;
+
+use crate::irq::{IrqError, IrqHandler};
+
+/// Main counter ticks per second, or `None` if the machine has no HPET.
+pub fn frequency() -> Option<u64> {
+    match unsafe { kernel_hpet_frequency() } {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// The main counter, extended to 64 bits if the hardware counter is narrower. Always `0` without an
+/// HPET.
+pub fn counter() -> u64 {
+    unsafe { kernel_hpet_counter() }
+}
+
+/// A comparator claimed with [`claim_comparator`]. Dropping it disarms and releases it.
+#[derive(Debug)]
+pub struct Comparator {
+    n: u8,
+}
+
+impl Comparator {
+    /// Interrupts once the main counter reaches `deadline`, replacing any deadline armed before. A
+    /// deadline that has already passed only fires once the counter wraps around, so check
+    /// [`counter`] afterwards. Some comparators are only 32 bits wide, and fire after one
+    /// wraparound instead of at a deadline further out.
+    pub fn arm(&self, deadline: u64) {
+        unsafe { kernel_arm_hpet_comparator(self.n, deadline) }
+    }
+
+    pub fn disarm(&self) {
+        unsafe { kernel_disarm_hpet_comparator(self.n) }
+    }
+}
+
+impl Drop for Comparator {
+    fn drop(&mut self) {
+        unsafe { kernel_release_hpet_comparator(self.n) }
+    }
+}
+
+/// Claims a free comparator and has it call `handler` with `data` each time it fires. The
+/// comparator starts out disarmed.
+///
+/// Fails with [`IrqError::NoSuchLine`] if there is no HPET or the comparators' interrupt lines
+/// can't be routed, and with [`IrqError::NoFreeVector`] if every comparator is claimed.
+///
+/// # Safety
+/// As for [`crate::irq::register_irq`].
+pub unsafe fn claim_comparator(
+    handler: IrqHandler,
+    data: *mut c_void,
+) -> Result<Comparator, IrqError> {
+    let mut n = 0;
+    IrqError::from_status(unsafe { kernel_claim_hpet_comparator(handler, data, &mut n) })?;
+    Ok(Comparator { n })
+}
+
+unsafe extern "C" {
+    unsafe fn kernel_hpet_frequency() -> u64;
+    unsafe fn kernel_hpet_counter() -> u64;
+    unsafe fn kernel_claim_hpet_comparator(
+        handler: IrqHandler,
+        data: *mut c_void,
+        n: *mut u8,
+    ) -> u32;
+    unsafe fn kernel_arm_hpet_comparator(n: u8, deadline: u64);
+    unsafe fn kernel_disarm_hpet_comparator(n: u8);
+    unsafe fn kernel_release_hpet_comparator(n: u8);
+}
+
+crate::require_symbol!(kernel_hpet_frequency, 1);
+crate::require_symbol!(kernel_hpet_counter, 1);
+crate::require_symbol!(kernel_claim_hpet_comparator, 1);
+crate::require_symbol!(kernel_arm_hpet_comparator, 1);
+crate::require_symbol!(kernel_disarm_hpet_comparator, 1);
+crate::require_symbol!(kernel_release_hpet_comparator, 1);
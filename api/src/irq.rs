@@ -0,0 +1,136 @@
+//! Claiming hardware interrupt lines.
+//!
+//! Lines are identified by their ACPI global system interrupt (GSI) number. The loader assigns each
+//! claimed line a free vector, routes it through the I/O APIC that serves it and, when it fires,
+//! calls every handler registered on it, then acknowledges it. A line stays claimed until every
+//! [`IrqHandle`] for it has been dropped, or the modules that registered its handlers are unloaded.
+
+use core::ffi::c_void;
+
+/// Called with interrupts disabled when the line fires, with the `data` given at registration.
+/// Handlers must not register or unregister interrupts themselves.
+pub type IrqHandler = unsafe extern "C" fn(data: *mut c_void) -> IrqStatus;
+
+fake_enum::fake_enum! {
+    /// What a handler made of an interrupt
+    #[repr(u32)]
+    pub enum struct IrqStatus {
+        /// The device the handler drives didn't raise it, so other handlers on a shared line should
+        /// be asked
+        NotMine = 0,
+        /// The handler serviced its device
+        Handled = 1,
+    }
+}
+
+/// How an interrupt line signals, and whether it may be shared. The default is an edge triggered,
+/// active high line claimed exclusively, which is what ISA interrupts are.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IrqFlags(u32);
+
+impl IrqFlags {
+    pub const EDGE: Self = Self(0);
+    pub const LEVEL_TRIGGERED: Self = Self(1);
+    pub const ACTIVE_LOW: Self = Self(2);
+    /// Other handlers may be registered on the line, as long as they agree on the other flags.
+    /// PCI interrupts are usually shared, level triggered and active low.
+    pub const SHARED: Self = Self(4);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags other than [`IrqFlags::SHARED`], which every handler on a line must agree on.
+    pub const fn signalling(self) -> Self {
+        Self(self.0 & (Self::LEVEL_TRIGGERED.0 | Self::ACTIVE_LOW.0))
+    }
+}
+
+impl core::ops::BitOr for IrqFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct IrqError {
+        /// No I/O APIC serves the GSI
+        NoSuchLine = 1,
+        /// Every vector available for device interrupts is in use
+        NoFreeVector = 2,
+        /// The line is claimed exclusively, or with different flags
+        Busy = 3,
+    }
+}
+
+crate::helpers::status_error!(IrqError);
+
+/// A handler registered with [`register_irq`]. Dropping it unregisters the handler, and releases
+/// the line if it was the last one on it.
+#[derive(Debug)]
+pub struct IrqHandle {
+    id: u64,
+    vector: u8,
+}
+
+impl IrqHandle {
+    /// The vector the line was assigned.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+impl Drop for IrqHandle {
+    fn drop(&mut self) {
+        unsafe { kernel_unregister_irq(self.id) }
+    }
+}
+
+/// Registers `handler` to be called with `data` whenever the interrupt line `gsi` fires, claiming
+/// and unmasking the line if nothing else has.
+///
+/// # Safety
+/// `handler` must be safe to call with `data` from interrupt context until the returned handle is
+/// dropped.
+pub unsafe fn register_irq(
+    gsi: u32,
+    handler: IrqHandler,
+    data: *mut c_void,
+    flags: IrqFlags,
+) -> Result<IrqHandle, IrqError> {
+    let mut id = 0;
+    let mut vector = 0;
+    IrqError::from_status(unsafe {
+        kernel_register_irq(gsi, handler, data, flags, &mut id, &mut vector)
+    })?;
+    Ok(IrqHandle { id, vector })
+}
+
+/// The GSI the ISA interrupt `irq` arrives at, and the flags to register it with. The firmware may
+/// remap ISA interrupts or change how they signal.
+pub fn isa_irq(irq: u8) -> (u32, IrqFlags) {
+    let mut gsi = 0;
+    let flags = unsafe { kernel_isa_irq(irq, &mut gsi) };
+    (gsi, flags)
+}
+
+unsafe extern "C" {
+    unsafe fn kernel_register_irq(
+        gsi: u32,
+        handler: IrqHandler,
+        data: *mut c_void,
+        flags: IrqFlags,
+        id: *mut u64,
+        vector: *mut u8,
+    ) -> u32;
+    unsafe fn kernel_unregister_irq(id: u64);
+    unsafe fn kernel_isa_irq(irq: u8, gsi: *mut u32) -> IrqFlags;
+}
+
+crate::require_symbol!(kernel_register_irq, 1);
+crate::require_symbol!(kernel_unregister_irq, 1);
+crate::require_symbol!(kernel_isa_irq, 1);
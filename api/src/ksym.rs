@@ -0,0 +1,86 @@
+//! Versioned tables of the symbols the loader and modules export to other modules.
+//!
+//! Modules may only bind to symbols that something has listed with
+//! [`export_symbol!`](crate::export_symbol). Each export carries a version, which is bumped when
+//! the symbol changes incompatibly; a module that declares the version it was built against with
+//! [`require_symbol!`](crate::require_symbol) is refused if the exported version differs. Imports
+//! without a declared version accept any version of the symbol.
+//!
+//! Like [`ModInfo`](crate::module::ModInfo), the entries are stored inline so the loader can read
+//! them straight out of module files.
+
+use core::ffi::CStr;
+
+use crate::module::{fixed_str, unfixed_str};
+
+/// Name of the section [`export_symbol!`](crate::export_symbol) places entries in
+pub const KSYMTAB_SECTION: &CStr = c".lilium.ksymtab";
+
+/// Name of the section [`require_symbol!`](crate::require_symbol) places entries in
+pub const KSYMREQ_SECTION: &CStr = c".lilium.ksymreq";
+
+pub const SYMBOL_NAME_LEN: usize = 128;
+
+/// A symbol name and version, either exported or required.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SymbolVersion {
+    pub name: [u8; SYMBOL_NAME_LEN],
+    pub version: u32,
+}
+
+impl SymbolVersion {
+    pub const fn new(name: &str, version: u32) -> Self {
+        Self {
+            name: fixed_str(name),
+            version,
+        }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unfixed_str(&self.name)
+    }
+}
+
+/// Exports the function or static `name` to modules at `version`. The item itself must also be
+/// `#[unsafe(no_mangle)]`.
+///
+/// ```ignore
+/// #[unsafe(no_mangle)]
+/// extern "C" fn print_bytes(data: *const u8, len: usize) { ... }
+/// los_api::export_symbol!(print_bytes, 1);
+/// ```
+#[macro_export]
+macro_rules! export_symbol {
+    ($name:ident, $version:expr $(,)?) => {
+        const _: () = {
+            // Exported for the same reason as the module info, see `module_info!`
+            #[used]
+            #[unsafe(export_name = concat!("__lilium_ksymtab_", stringify!($name)))]
+            #[unsafe(link_section = ".lilium.ksymtab")]
+            static ENTRY: $crate::ksym::SymbolVersion =
+                $crate::ksym::SymbolVersion::new(stringify!($name), $version);
+        };
+    };
+}
+
+/// Declares that the current module imports `name` and was built against `version` of it.
+///
+/// ```ignore
+/// unsafe extern "C" {
+///     unsafe fn print_bytes(data: *const u8, len: usize);
+/// }
+/// los_api::require_symbol!(print_bytes, 1);
+/// ```
+#[macro_export]
+macro_rules! require_symbol {
+    ($name:ident, $version:expr $(,)?) => {
+        const _: () = {
+            #[used]
+            #[unsafe(export_name = concat!("__lilium_ksymreq_", stringify!($name)))]
+            #[unsafe(link_section = ".lilium.ksymreq")]
+            static ENTRY: $crate::ksym::SymbolVersion =
+                $crate::ksym::SymbolVersion::new(stringify!($name), $version);
+        };
+    };
+}
@@ -34,7 +34,8 @@ macro_rules! println {
 
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
-    println!("\x1b[31;1merror: the OS encountered a panic. {info}");
+    println!("\x1b[31;1merror: the OS encountered a panic. {info}\x1b[0m");
+    unsafe { print_backtrace() };
     hcf();
 }
 
@@ -47,8 +48,14 @@ unsafe extern "C" {
     safe fn hcf_real() -> !;
 
     unsafe fn print_bytes(data: *const u8, len: usize);
+
+    unsafe fn print_backtrace();
 }
 
+crate::require_symbol!(hcf_real, 1);
+crate::require_symbol!(print_bytes, 1);
+crate::require_symbol!(print_backtrace, 1);
+
 pub mod auxv;
 
 pub mod arch;
@@ -56,3 +63,19 @@ pub mod arch;
 pub mod rand;
 
 pub mod helpers;
+
+pub mod mem;
+
+pub mod module;
+
+pub mod ksym;
+
+pub mod cmdline;
+
+pub mod irq;
+
+pub mod timer;
+
+pub mod time;
+
+pub mod hpet;
@@ -0,0 +1,200 @@
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    /// Bytes currently handed out to allocations
+    pub used: usize,
+    /// Bytes the heap has claimed from physical memory but not handed out
+    pub free: usize,
+    /// Highest value `used` has reached since boot
+    pub high_water_mark: usize,
+}
+
+/// Returns usage statistics for the kernel heap shared by the loader and all modules.
+pub fn heap_stats() -> HeapStats {
+    let mut stats = HeapStats::default();
+    unsafe {
+        get_heap_stats(&mut stats);
+    }
+    stats
+}
+
+fake_enum::fake_enum! {
+    /// Regions of the kernel half of the address space managed by the loader
+    #[repr(u32)]
+    pub enum struct AddressRegion {
+        /// The kernel heap, which grows in place and can't be reserved from
+        Heap = 0,
+        /// Kernel module images
+        Modules = 1,
+        /// Device memory and other physical ranges mapped on demand
+        Mmio = 2,
+        /// Kernel stacks
+        Stacks = 3,
+    }
+}
+
+fake_enum::fake_enum! {
+    /// Memory type used for a mapping. Device memory should be [`CacheMode::Uncached`], and linear
+    /// framebuffers are usually best mapped [`CacheMode::WriteCombining`].
+    #[repr(u32)]
+    pub enum struct CacheMode {
+        WriteBack = 0,
+        WriteThrough = 1,
+        Uncached = 2,
+        WriteCombining = 3,
+    }
+}
+
+/// Access permissions of a mapping. Mappings are always readable.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Protection(u32);
+
+impl Protection {
+    pub const READ: Self = Self(0);
+    pub const WRITE: Self = Self(1);
+    pub const EXEC: Self = Self(2);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Protection {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct MapError {
+        OutOfMemory = 1,
+        OutOfAddressSpace = 2,
+        AlreadyMapped = 3,
+        NotMapped = 4,
+        InvalidArgument = 5,
+    }
+}
+
+crate::helpers::status_error!(MapError);
+
+/// Reserves `size` bytes of address space aligned to `align` in `region`, without mapping anything.
+pub fn reserve_virtual(
+    region: AddressRegion,
+    size: usize,
+    align: usize,
+) -> Result<*mut core::ffi::c_void, MapError> {
+    let mut addr = core::ptr::null_mut();
+    MapError::from_status(unsafe { kernel_reserve_virtual(region, size, align, &mut addr) })?;
+    Ok(addr)
+}
+
+/// Returns address space reserved with [`reserve_virtual`]. Fails with
+/// [`MapError::InvalidArgument`] if the range isn't page aligned, lies outside `region` or is
+/// already partly free.
+///
+/// # Safety
+/// `[addr, addr + size)` must have been reserved from `region` and must no longer be mapped.
+pub unsafe fn release_virtual(
+    region: AddressRegion,
+    addr: *mut core::ffi::c_void,
+    size: usize,
+) -> Result<(), MapError> {
+    MapError::from_status(unsafe { kernel_release_virtual(region, addr, size) })
+}
+
+/// Maps `[phys, phys + size)` at `virt`. Both addresses and `size` must be page aligned.
+///
+/// # Safety
+/// `virt` must be address space reserved by the caller, and the physical range must be memory the
+/// caller is entitled to access.
+pub unsafe fn map_range(
+    virt: *mut core::ffi::c_void,
+    phys: u64,
+    size: usize,
+    prot: Protection,
+    cache: CacheMode,
+) -> Result<(), MapError> {
+    MapError::from_status(unsafe { kernel_map_range(virt, phys, size, prot, cache) })
+}
+
+/// # Safety
+/// Nothing may access `[virt, virt + size)` after the call.
+pub unsafe fn unmap_range(virt: *mut core::ffi::c_void, size: usize) -> Result<(), MapError> {
+    MapError::from_status(unsafe { kernel_unmap_range(virt, size) })
+}
+
+/// # Safety
+/// Removing permissions from memory still in use by Rust code is undefined behaviour.
+pub unsafe fn protect_range(
+    virt: *mut core::ffi::c_void,
+    size: usize,
+    prot: Protection,
+) -> Result<(), MapError> {
+    MapError::from_status(unsafe { kernel_protect_range(virt, size, prot) })
+}
+
+/// Maps the physical range `[phys, phys + size)` read-write into the MMIO region. `phys` need not
+/// be page aligned; the returned pointer corresponds to `phys` itself.
+///
+/// # Safety
+/// The physical range must be device memory (or otherwise not owned by the frame allocator).
+pub unsafe fn map_mmio(phys: u64, size: usize, cache: CacheMode) -> Result<*mut u8, MapError> {
+    let mut addr = core::ptr::null_mut();
+    MapError::from_status(unsafe { kernel_map_mmio(phys, size, cache, &mut addr) })?;
+    Ok(addr.cast())
+}
+
+/// # Safety
+/// `virt` and `size` must be exactly as passed to and returned from [`map_mmio`], and the mapping
+/// must no longer be accessed.
+pub unsafe fn unmap_mmio(virt: *mut u8, size: usize) -> Result<(), MapError> {
+    MapError::from_status(unsafe { kernel_unmap_mmio(virt.cast(), size) })
+}
+
+unsafe extern "C" {
+    unsafe fn get_heap_stats(stats: *mut HeapStats);
+    unsafe fn kernel_reserve_virtual(
+        region: AddressRegion,
+        size: usize,
+        align: usize,
+        addr: *mut *mut core::ffi::c_void,
+    ) -> u32;
+    unsafe fn kernel_release_virtual(
+        region: AddressRegion,
+        addr: *mut core::ffi::c_void,
+        size: usize,
+    ) -> u32;
+    unsafe fn kernel_map_range(
+        virt: *mut core::ffi::c_void,
+        phys: u64,
+        size: usize,
+        prot: Protection,
+        cache: CacheMode,
+    ) -> u32;
+    unsafe fn kernel_unmap_range(virt: *mut core::ffi::c_void, size: usize) -> u32;
+    unsafe fn kernel_protect_range(
+        virt: *mut core::ffi::c_void,
+        size: usize,
+        prot: Protection,
+    ) -> u32;
+    unsafe fn kernel_map_mmio(
+        phys: u64,
+        size: usize,
+        cache: CacheMode,
+        addr: *mut *mut core::ffi::c_void,
+    ) -> u32;
+    unsafe fn kernel_unmap_mmio(virt: *mut core::ffi::c_void, size: usize) -> u32;
+}
+
+crate::require_symbol!(get_heap_stats, 1);
+crate::require_symbol!(kernel_reserve_virtual, 1);
+crate::require_symbol!(kernel_release_virtual, 1);
+crate::require_symbol!(kernel_map_range, 1);
+crate::require_symbol!(kernel_unmap_range, 1);
+crate::require_symbol!(kernel_protect_range, 1);
+crate::require_symbol!(kernel_map_mmio, 1);
+crate::require_symbol!(kernel_unmap_mmio, 1);
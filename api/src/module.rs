@@ -0,0 +1,310 @@
+//! Self-description of kernel modules, read by the loader straight out of the module file.
+//!
+//! Use [`module_info!`](crate::module_info) once per module. Everything in [`ModInfo`] is stored
+//! inline rather than behind pointers, so the loader can read it before the module is relocated.
+
+use core::ffi::{CStr, c_char};
+
+use crate::cmdline::{parse_bool, parse_int};
+
+/// Version of the interface between the loader and modules. Bumped whenever a change to `los-api`
+/// means modules built against an older version can no longer be loaded.
+pub const ABI_VERSION: u32 = 1;
+
+pub const MODINFO_MAGIC: [u8; 8] = *b"LILMODI\0";
+
+/// Name of the section [`module_info!`](crate::module_info) places the [`ModInfo`] in
+pub const MODINFO_SECTION: &CStr = c".lilium.modinfo";
+
+pub const NAME_LEN: usize = 64;
+pub const VERSION_LEN: usize = 32;
+pub const LICENSE_LEN: usize = 32;
+pub const MAX_DEPENDENCIES: usize = 16;
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct DependencyKind {
+        /// An unused slot of [`ModInfo::dependencies`]
+        None = 0,
+        /// The module can't work without the dependency, and isn't loaded if the dependency is
+        /// missing or fails
+        Hard = 1,
+        /// The dependency is loaded and initialized first if present, but is optional
+        Soft = 2,
+    }
+}
+
+/// Copies `s` into a NUL-padded array, failing (at compile time, when used in a `static`) if it
+/// doesn't fit with at least one NUL.
+pub(crate) const fn fixed_str<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    assert!(bytes.len() < N, "string too long for its fixed-size field");
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+/// The string stored by [`fixed_str`], or an empty string if the bytes aren't NUL-terminated.
+pub(crate) fn unfixed_str(bytes: &[u8]) -> &CStr {
+    CStr::from_bytes_until_nul(bytes).unwrap_or(c"")
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Dependency {
+    pub kind: DependencyKind,
+    pub name: [u8; NAME_LEN],
+}
+
+impl Dependency {
+    const NONE: Self = Self {
+        kind: DependencyKind::None,
+        name: [0; NAME_LEN],
+    };
+
+    pub const fn hard(name: &str) -> Self {
+        Self {
+            kind: DependencyKind::Hard,
+            name: fixed_str(name),
+        }
+    }
+
+    pub const fn soft(name: &str) -> Self {
+        Self {
+            kind: DependencyKind::Soft,
+            name: fixed_str(name),
+        }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unfixed_str(&self.name)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ModInfo {
+    pub magic: [u8; 8],
+    pub abi_version: u32,
+    pub name: [u8; NAME_LEN],
+    pub version: [u8; VERSION_LEN],
+    pub license: [u8; LICENSE_LEN],
+    pub dependencies: [Dependency; MAX_DEPENDENCIES],
+}
+
+impl ModInfo {
+    pub const fn new(name: &str, version: &str, license: &str, deps: &[Dependency]) -> Self {
+        assert!(
+            deps.len() <= MAX_DEPENDENCIES,
+            "too many module dependencies"
+        );
+        let mut dependencies = [Dependency::NONE; MAX_DEPENDENCIES];
+        let mut i = 0;
+        while i < deps.len() {
+            dependencies[i] = deps[i];
+            i += 1;
+        }
+        Self {
+            magic: MODINFO_MAGIC,
+            abi_version: ABI_VERSION,
+            name: fixed_str(name),
+            version: fixed_str(version),
+            license: fixed_str(license),
+            dependencies,
+        }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unfixed_str(&self.name)
+    }
+
+    pub fn version(&self) -> &CStr {
+        unfixed_str(&self.version)
+    }
+
+    pub fn license(&self) -> &CStr {
+        unfixed_str(&self.license)
+    }
+
+    pub fn dependencies(&self) -> impl Iterator<Item = &Dependency> {
+        self.dependencies
+            .iter()
+            .filter(|dep| dep.kind != DependencyKind::None)
+    }
+}
+
+/// Describes the current module to the loader.
+///
+/// ```ignore
+/// los_api::module_info! {
+///     name: "hello_world",
+///     version: env!("CARGO_PKG_VERSION"),
+///     license: "MIT OR Apache-2.0",
+///     dependencies: [hard "lilium-kernel"],
+/// }
+/// ```
+#[macro_export]
+macro_rules! module_info {
+    (
+        name: $name:expr,
+        version: $version:expr,
+        license: $license:expr
+        $(, dependencies: [$($kind:ident $dep:expr),* $(,)?])?
+        $(,)?
+    ) => {
+        // Exported rather than just `#[used]`, which doesn't stop the linker from discarding the
+        // section. The name is the crate's own, as every module exports one; the loader reads the
+        // section, not the symbol
+        #[used]
+        #[unsafe(export_name = concat!("__lilium_modinfo_", env!("CARGO_CRATE_NAME")))]
+        #[unsafe(link_section = ".lilium.modinfo")]
+        pub static __LILIUM_MODINFO: $crate::module::ModInfo = $crate::module::ModInfo::new(
+            $name,
+            $version,
+            $license,
+            &[$($($crate::module::Dependency::$kind($dep)),*)?],
+        );
+    };
+}
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct UnloadError {
+        /// No loaded module has that name
+        NotFound = 1,
+        /// Another loaded module still imports symbols from it or depends on it
+        InUse = 2,
+    }
+}
+
+crate::helpers::status_error!(UnloadError);
+
+/// Asks the loader to finalize and unmap the module `name` (its module info name or its file name).
+/// Intended for development; a module must not unload itself.
+pub fn unload_module(name: &CStr) -> Result<(), UnloadError> {
+    UnloadError::from_status(unsafe { kernel_unload_module(name.as_ptr()) })
+}
+
+fake_enum::fake_enum! {
+    /// Result of looking up a module parameter
+    #[repr(u32)]
+    pub enum struct ParamLookup {
+        /// The module string doesn't mention the key
+        Absent = 0,
+        /// The key is given bare, without `=value`
+        Flag = 1,
+        /// The key is given with a value
+        Value = 2,
+    }
+}
+
+/// Looks up `key` (case-insensitively) in the module string of the module containing `anchor`,
+/// which is any address in the calling module, such as that of a `static`.
+///
+/// Returns `None` if the key isn't given, `Some(None)` if it is given as a bare flag, and
+/// `Some(Some(value))` for `key=value`. The value stays valid until the module is unloaded.
+pub fn lookup_param(anchor: *const u8, key: &str) -> Option<Option<&'static str>> {
+    let mut value = core::ptr::null();
+    let mut len = 0;
+    match unsafe { kernel_module_param(anchor, key.as_ptr(), key.len(), &mut value, &mut len) } {
+        ParamLookup::Flag => Some(None),
+        ParamLookup::Value => Some(Some(unsafe {
+            core::str::from_utf8_unchecked(core::slice::from_raw_parts(value, len))
+        })),
+        _ => None,
+    }
+}
+
+/// Types a module parameter can be declared as with [`module_param!`](crate::module_param).
+pub trait ParamValue: Copy + Sized + 'static {
+    /// Parses the parameter, given as a bare flag when `value` is `None`.
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value.map_or(Some(true), parse_bool)
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value
+    }
+}
+
+macro_rules! int_param_value {
+    ($($ty:ty),*) => {
+        $(
+            impl ParamValue for $ty {
+                fn parse(value: Option<&'static str>) -> Option<Self> {
+                    parse_int(value?)
+                }
+            }
+        )*
+    };
+}
+
+int_param_value!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// A module parameter declared with [`module_param!`](crate::module_param), set from the module
+/// string in `limine.conf` as `name=value` (or just `name`, for flags).
+pub struct Param<T> {
+    name: &'static str,
+    default: T,
+}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(name: &'static str, default: T) -> Self {
+        Self { name, default }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The value given at boot, or the default if it wasn't given or doesn't parse.
+    pub fn get(&'static self) -> T {
+        lookup_param((self as *const Self).cast(), self.name)
+            .and_then(T::parse)
+            .unwrap_or(self.default)
+    }
+}
+
+/// Declares typed module parameters, named after the `static` (matched case-insensitively).
+///
+/// ```ignore
+/// los_api::module_param! {
+///     /// Who to greet
+///     static GREETING: &str = "world";
+///     static VERBOSE: bool = false;
+/// }
+/// ```
+#[macro_export]
+macro_rules! module_param {
+    ($($(#[$meta:meta])* $vis:vis static $name:ident: $ty:ty = $default:expr;)*) => {
+        $(
+            $(#[$meta])*
+            $vis static $name: $crate::module::Param<$ty> =
+                $crate::module::Param::new(stringify!($name), $default);
+        )*
+    };
+}
+
+unsafe extern "C" {
+    unsafe fn kernel_unload_module(name: *const c_char) -> u32;
+    unsafe fn kernel_module_param(
+        anchor: *const u8,
+        key: *const u8,
+        key_len: usize,
+        value: *mut *const u8,
+        value_len: *mut usize,
+    ) -> ParamLookup;
+}
+
+crate::require_symbol!(kernel_unload_module, 1);
+crate::require_symbol!(kernel_module_param, 1);
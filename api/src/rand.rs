@@ -15,6 +15,13 @@ use rand_core::{
 pub struct CsRand(raw_shake256!(128));
 
 impl CsRand {
+    /// A generator seeded with `seed`, such as the buffer the loader gathers at boot.
+    pub fn new(seed: &[u8]) -> Self {
+        let mut rand = Self(Default::default());
+        rand.push_enthropy(seed);
+        rand
+    }
+
     pub fn push_enthropy(&mut self, enthropy: &[u8]) {
         let chunks = ByteArray::array_chunks(enthropy);
         let rem = chunks.remainder();
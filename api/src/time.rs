@@ -0,0 +1,36 @@
+//! Reading the time.
+//!
+//! The loader picks a clocksource at boot: the TSC when the CPU reports it as invariant, otherwise
+//! the HPET, or the PIT as a last resort. `--clocksource tsc|hpet|pit` on the command line
+//! overrides the choice.
+
+fake_enum::fake_enum! {
+    /// The counter [`monotonic_now`] reads
+    #[repr(u32)]
+    pub enum struct Clocksource {
+        /// The CPU's time stamp counter
+        Tsc = 0,
+        /// The main counter of the High Precision Event Timer
+        Hpet = 1,
+        /// PIT channel 0, free running. Only precise to a little under a microsecond
+        Pit = 2,
+    }
+}
+
+/// Nanoseconds since the clocksource was set up at boot. Never goes backwards.
+pub fn monotonic_now() -> u64 {
+    unsafe { kernel_monotonic_now() }
+}
+
+/// The clocksource the loader picked.
+pub fn clocksource() -> Clocksource {
+    unsafe { kernel_clocksource() }
+}
+
+unsafe extern "C" {
+    unsafe fn kernel_monotonic_now() -> u64;
+    unsafe fn kernel_clocksource() -> Clocksource;
+}
+
+crate::require_symbol!(kernel_monotonic_now, 1);
+crate::require_symbol!(kernel_clocksource, 1);
@@ -0,0 +1,81 @@
+//! The kernel tick.
+//!
+//! The loader calibrates the local APIC timer at boot and counts time in ticks of
+//! `1 / TICK_HZ` seconds, read from the clocksource (see [`crate::time`]). In
+//! [`TimerMode::Periodic`] the timer interrupts every tick; in [`TimerMode::OneShot`] only when a
+//! deadline set with [`arm_oneshot`] passes, which suits a tickless kernel. Either way, every
+//! registered callback is called on each timer interrupt.
+
+use core::ffi::c_void;
+
+/// Ticks per second
+pub const TICK_HZ: u64 = 1000;
+
+/// Called with interrupts disabled on each timer interrupt, with the current tick count and the
+/// `data` given at registration. Callbacks must not register or unregister callbacks themselves.
+pub type TickCallback = unsafe extern "C" fn(now: u64, data: *mut c_void);
+
+fake_enum::fake_enum! {
+    #[repr(u32)]
+    pub enum struct TimerMode {
+        /// Interrupt every tick
+        Periodic = 0,
+        /// Interrupt only once each deadline armed with [`arm_oneshot`] passes
+        OneShot = 1,
+    }
+}
+
+/// Ticks since the clocksource was set up at boot. Never goes backwards.
+pub fn ticks() -> u64 {
+    unsafe { kernel_ticks() }
+}
+
+/// A callback registered with [`register_tick`]. Dropping it unregisters the callback.
+#[derive(Debug)]
+pub struct TickHandle {
+    id: u64,
+}
+
+impl Drop for TickHandle {
+    fn drop(&mut self) {
+        unsafe { kernel_unregister_tick(self.id) }
+    }
+}
+
+/// Registers `callback` to be called with `data` on every timer interrupt.
+///
+/// # Safety
+/// `callback` must be safe to call with `data` from interrupt context until the returned handle is
+/// dropped.
+pub unsafe fn register_tick(callback: TickCallback, data: *mut c_void) -> TickHandle {
+    TickHandle {
+        id: unsafe { kernel_register_tick(callback, data) },
+    }
+}
+
+/// Switches the timer to `mode`. Switching to [`TimerMode::OneShot`] leaves the timer idle until a
+/// deadline is armed, except that a clocksource that wraps around quickly (the PIT, or a 32-bit
+/// HPET) has it interrupt often enough to keep track of it.
+pub fn set_timer_mode(mode: TimerMode) {
+    unsafe { kernel_set_timer_mode(mode) }
+}
+
+/// In [`TimerMode::OneShot`], interrupts once tick `deadline` is reached, or straight away if it
+/// already has, replacing any deadline armed before. Does nothing in [`TimerMode::Periodic`].
+pub fn arm_oneshot(deadline: u64) {
+    unsafe { kernel_arm_oneshot(deadline) }
+}
+
+unsafe extern "C" {
+    unsafe fn kernel_ticks() -> u64;
+    unsafe fn kernel_register_tick(callback: TickCallback, data: *mut c_void) -> u64;
+    unsafe fn kernel_unregister_tick(id: u64);
+    unsafe fn kernel_set_timer_mode(mode: TimerMode);
+    unsafe fn kernel_arm_oneshot(deadline: u64);
+}
+
+crate::require_symbol!(kernel_ticks, 1);
+crate::require_symbol!(kernel_register_tick, 1);
+crate::require_symbol!(kernel_unregister_tick, 1);
+crate::require_symbol!(kernel_set_timer_mode, 1);
+crate::require_symbol!(kernel_arm_oneshot, 1);
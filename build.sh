@@ -22,7 +22,13 @@ rm -rf iso_root
 
 mkdir -p iso_root/boot/modules
 cp -v lilium-loader.so iso_root/boot/lilium-loader.so
+cp -v target/x86_64-pc-lilium-kernel/debug/liblilium_kernel.so iso_root/boot/modules/lilium-kernel.so
 cp -v target/x86_64-pc-lilium-kernel/debug/libhello_world.so iso_root/boot/modules/hello_world.so
+if [ -f keys/module-signing.pem ]; then
+    for module in iso_root/boot/modules/*.so; do
+        ./sign-module.sh keys/module-signing.pem "$module" || exit 1
+    done
+fi
 
 mkdir -p iso_root/boot/limine
 cp -v limine.conf iso_root/boot/limine
@@ -1,7 +1,23 @@
+use std::path::PathBuf;
+
 fn main() {
     let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
     // Tell cargo to pass the linker script to the linker..
     println!("cargo:rustc-link-arg=-Tlinker-{arch}.ld");
     // ... and to re-run if it changes.
     println!("cargo:rerun-if-changed=linker-{arch}.ld");
+
+    // Embed the raw Ed25519 public key modules are signed with (see `sign-module.sh`). Without one,
+    // no module verifies.
+    println!("cargo:rerun-if-env-changed=LILIUM_MODULE_KEY");
+    let key_path = std::env::var("LILIUM_MODULE_KEY")
+        .unwrap_or_else(|_| "../keys/module-signing.pub".to_string());
+    println!("cargo:rerun-if-changed={key_path}");
+    let key = std::fs::read(&key_path).unwrap_or_default();
+    assert!(
+        key.is_empty() || key.len() == 32,
+        "{key_path} is not a raw Ed25519 public key"
+    );
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("module-signing.pub"), key).unwrap();
 }
@@ -1,33 +1,49 @@
 use crate::{interrupt::InterruptIndex, prelude::*};
 
-use acpi::{AcpiTables, InterruptModel, PlatformInfo, platform::interrupt::Apic};
-use alloc::alloc::Global;
+use acpi::{
+    AcpiTables, HpetInfo, InterruptModel, PlatformInfo,
+    platform::interrupt::{Apic, LocalInterruptLine, NmiProcessor, Polarity, TriggerMode},
+};
+use alloc::{alloc::Global, vec::Vec};
+use los_api::{
+    irq::{IrqError, IrqFlags},
+    mem::CacheMode,
+};
 use x2apic::{
-    ioapic::{IoApic, IrqMode, RedirectionTableEntry},
+    ioapic::{IoApic, IrqFlags as RedirectionFlags, IrqMode, RedirectionTableEntry},
     lapic::{LocalApic, LocalApicBuilder},
 };
-use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, registers::model_specific::Msr};
 
 use crate::{
-    interrupt::IDT,
-    limine_requests::RSDP_REQUEST,
-    memory::{BasicAcpiHandler, map_physical_region},
+    boot_info::boot_info,
+    clocksource, hpet,
+    interrupt::{IDT, irq},
+    memory::{BasicAcpiHandler, boot_memory_reclaimed, virt::map_mmio},
+    timer,
     util::UnsafeSync,
 };
 
+/// ISA interrupts the loader handles itself
 #[derive(Clone, Copy)]
 #[repr(u8)]
 enum IrqVector {
     Keyboard = 1,
-    Mouse = 12,
 }
 
+/// The firmware ACPI tables. These live in `ACPI_RECLAIMABLE` memory, so anything needed from them
+/// must be parsed into an owned structure (like [`APIC`]) before
+/// [`crate::memory::reclaim_boot_memory`] runs.
 static ACPI: UnsafeSync<spin::Lazy<AcpiTables<BasicAcpiHandler>>> = unsafe {
     UnsafeSync::new(spin::Lazy::new(|| {
-        let Some(rsdp_response) = RSDP_REQUEST.get_response() else {
+        assert!(
+            !boot_memory_reclaimed(),
+            "ACPI tables accessed after boot memory was reclaimed"
+        );
+        let Some(rsdp) = boot_info().rsdp else {
             panic!("couldn't locate RSDP table");
         };
-        AcpiTables::from_rsdp(BasicAcpiHandler, rsdp_response.address()).unwrap()
+        AcpiTables::from_rsdp(BasicAcpiHandler, rsdp).unwrap()
     }))
 };
 
@@ -41,11 +57,26 @@ static APIC: UnsafeSync<spin::Lazy<Apic<'static, Global>>> = unsafe {
     }))
 };
 
+/// The HPET table, if the firmware provides one. Like [`APIC`], only available until boot memory is
+/// reclaimed.
+pub fn hpet_info() -> Option<HpetInfo> {
+    HpetInfo::new(&ACPI).ok()
+}
+
+static LAPIC_BASE: spin::Lazy<VirtAddr> = spin::Lazy::new(|| {
+    unsafe {
+        map_mmio(
+            PhysAddr::new(APIC.local_apic_address),
+            0x1000,
+            CacheMode::Uncached,
+        )
+    }
+    .expect("failed to map the local APIC")
+});
+
 pub fn lapic() -> LocalApic {
     LocalApicBuilder::new()
-        .set_xapic_base(
-            unsafe { map_physical_region(APIC.local_apic_address as usize, 1024) } as u64,
-        )
+        .set_xapic_base(LAPIC_BASE.as_u64())
         .timer_vector(InterruptIndex::Timer as usize)
         .error_vector(InterruptIndex::ApicError as usize)
         .spurious_vector(InterruptIndex::ApicSpurious as usize)
@@ -53,6 +84,179 @@ pub fn lapic() -> LocalApic {
         .unwrap()
 }
 
+/// An I/O APIC, which serves the GSIs `[gsi_base, gsi_base + lines)`.
+struct IoApicLines {
+    ioapic: IoApic,
+    gsi_base: u32,
+    lines: u32,
+}
+
+static IOAPICS: UnsafeSync<spin::Mutex<Vec<IoApicLines>>> =
+    unsafe { UnsafeSync::new(spin::Mutex::new(Vec::new())) };
+
+/// Calls `f` with the I/O APIC serving `gsi` and the GSI's index in its redirection table.
+fn with_ioapic<R>(gsi: u32, f: impl FnOnce(&mut IoApic, u8) -> R) -> Option<R> {
+    let mut ioapics = IOAPICS.lock();
+    let entry = ioapics
+        .iter_mut()
+        .find(|entry| (entry.gsi_base..entry.gsi_base + entry.lines).contains(&gsi))?;
+    Some(f(&mut entry.ioapic, (gsi - entry.gsi_base) as u8))
+}
+
+/// Applies the polarity and trigger mode given in the MADT to `default`, the signalling of the bus
+/// the interrupt comes from.
+fn madt_flags(polarity: Polarity, trigger_mode: TriggerMode, default: IrqFlags) -> IrqFlags {
+    let level = match trigger_mode {
+        TriggerMode::SameAsBus => default.contains(IrqFlags::LEVEL_TRIGGERED),
+        TriggerMode::Edge => false,
+        TriggerMode::Level => true,
+    };
+    let active_low = match polarity {
+        Polarity::SameAsBus => default.contains(IrqFlags::ACTIVE_LOW),
+        Polarity::ActiveHigh => false,
+        Polarity::ActiveLow => true,
+    };
+    let mut flags = IrqFlags::EDGE;
+    if level {
+        flags = flags | IrqFlags::LEVEL_TRIGGERED;
+    }
+    if active_low {
+        flags = flags | IrqFlags::ACTIVE_LOW;
+    }
+    flags
+}
+
+/// The GSI the ISA interrupt `irq` arrives at, and how it signals. ISA interrupts are identity
+/// mapped, edge triggered and active high, unless the MADT overrides them.
+pub fn isa_irq(irq: u8) -> (u32, IrqFlags) {
+    match APIC
+        .interrupt_source_overrides
+        .iter()
+        .find(|source| source.isa_source == irq)
+    {
+        Some(source) => (
+            source.global_system_interrupt,
+            madt_flags(source.polarity, source.trigger_mode, IrqFlags::EDGE),
+        ),
+        None => (irq as u32, IrqFlags::EDGE),
+    }
+}
+
+/// Writes the redirection entry for `gsi`, sending it to this CPU, and unmasks it.
+unsafe fn program_gsi(gsi: u32, mode: IrqMode, vector: u8, flags: IrqFlags) -> Option<()> {
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(mode);
+    entry.set_dest(unsafe { lapic().id() } as u8);
+    entry.set_vector(vector);
+    let mut redirection_flags = RedirectionFlags::empty();
+    if flags.contains(IrqFlags::LEVEL_TRIGGERED) {
+        redirection_flags |= RedirectionFlags::LEVEL_TRIGGERED;
+    }
+    if flags.contains(IrqFlags::ACTIVE_LOW) {
+        redirection_flags |= RedirectionFlags::LOW_ACTIVE;
+    }
+    entry.set_flags(redirection_flags);
+    with_ioapic(gsi, |ioapic, pin| unsafe {
+        ioapic.set_table_entry(pin, entry);
+        ioapic.enable_irq(pin);
+    })
+}
+
+/// Routes `gsi` to `vector` on this CPU and unmasks it.
+///
+/// # Safety
+/// `vector` must have a handler that acknowledges the interrupt.
+pub unsafe fn route_gsi(gsi: u32, vector: u8, flags: IrqFlags) -> Result<(), IrqError> {
+    unsafe { program_gsi(gsi, IrqMode::Fixed, vector, flags) }.ok_or(IrqError::NoSuchLine)
+}
+
+/// Masks `gsi`, if an I/O APIC serves it.
+///
+/// # Safety
+/// Whatever the line is routed to must be able to cope with losing its interrupts.
+pub unsafe fn mask_gsi(gsi: u32) {
+    with_ioapic(gsi, |ioapic, pin| unsafe { ioapic.disable_irq(pin) });
+}
+
+/// Offsets of the local APIC's `LINT0` and `LINT1` LVT registers
+const LVT_LINT: [usize; 2] = [0x350, 0x360];
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+/// Set in `IA32_APIC_BASE` while the local APIC is in x2APIC mode
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// In x2APIC mode, the register at MMIO offset `offset` is MSR `X2APIC_MSR_BASE + offset / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Whether the local APIC is in x2APIC mode, which [`LocalApic::enable`] switches to when CPUID
+/// reports it. The MMIO page is ignored then, and the registers are only reachable as MSRs.
+fn x2apic_mode() -> bool {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    apic_base & APIC_BASE_X2APIC != 0
+}
+
+/// Reads the local APIC register at `offset`, for those [`LocalApic`] doesn't cover. `offset` is
+/// the register's xAPIC MMIO offset, and is translated to its MSR in x2APIC mode.
+///
+/// # Safety
+/// `offset` must be a readable register.
+pub unsafe fn lapic_read(offset: usize) -> u32 {
+    if x2apic_mode() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).read() as u32 }
+    } else {
+        unsafe { ((LAPIC_BASE.as_u64() as usize + offset) as *const u32).read_volatile() }
+    }
+}
+
+/// # Safety
+/// `offset` must be a writable register, and `value` must not break anything [`LocalApic`] or the
+/// rest of the loader relies on.
+pub unsafe fn lapic_write(offset: usize, value: u32) {
+    if x2apic_mode() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).write(value as u64) }
+    } else {
+        unsafe { ((LAPIC_BASE.as_u64() as usize + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+/// Delivers the NMI sources the MADT lists as NMIs: I/O APIC inputs, and the local APIC's `LINT`
+/// pins wired to them on every processor.
+unsafe fn route_nmis() {
+    for source in &APIC.nmi_sources {
+        let flags = madt_flags(source.polarity, source.trigger_mode, IrqFlags::EDGE);
+        if unsafe {
+            program_gsi(
+                source.global_system_interrupt,
+                IrqMode::NonMaskable,
+                0,
+                flags,
+            )
+        }
+        .is_none()
+        {
+            println!(
+                "No I/O APIC serves NMI source GSI {}",
+                source.global_system_interrupt
+            );
+        }
+    }
+
+    for nmi in &APIC.local_apic_lint_nmis {
+        // Only the boot processor is running, and its UID isn't kept
+        if !matches!(nmi.processor, NmiProcessor::All) {
+            continue;
+        }
+        let offset = match nmi.line {
+            LocalInterruptLine::Lint0 => LVT_LINT[0],
+            LocalInterruptLine::Lint1 => LVT_LINT[1],
+        };
+        // NMIs are always edge triggered, and the pins active high
+        unsafe {
+            lapic_write(offset, LVT_DELIVERY_NMI);
+        }
+    }
+}
+
 pub fn init() {
     let mut lapic = lapic();
 
@@ -60,12 +264,27 @@ pub fn init() {
 
     for ioapic in &*APIC.io_apics {
         unsafe {
-            let mut ioapic = IoApic::new(map_physical_region(ioapic.address as usize, 1024) as u64);
+            let base = map_mmio(
+                PhysAddr::new(ioapic.address as u64),
+                0x1000,
+                CacheMode::Uncached,
+            )
+            .expect("failed to map IOAPIC");
+            let gsi_base = ioapic.global_system_interrupt_base;
+            let mut ioapic = IoApic::new(base.as_u64());
             println!("{ioapic:?}");
 
-            ioapic.init(32);
-            add_ioapic_entry(&mut ioapic, IrqVector::Keyboard, InterruptIndex::Keyboard);
-            add_ioapic_entry(&mut ioapic, IrqVector::Mouse, InterruptIndex::Mouse);
+            ioapic.init(*irq::IRQ_VECTORS.start());
+            let lines = ioapic.max_table_entry() as u32 + 1;
+            // Everything starts out masked, until it is routed
+            for pin in 0..lines {
+                ioapic.disable_irq(pin as u8);
+            }
+            IOAPICS.lock().push(IoApicLines {
+                ioapic,
+                gsi_base,
+                lines,
+            });
         }
     }
 
@@ -73,19 +292,23 @@ pub fn init() {
 
     unsafe {
         lapic.enable();
+        route_nmis();
     }
 
-    interrupts::enable();
-}
+    hpet::init();
+    timer::init();
+    clocksource::init();
 
-unsafe fn add_ioapic_entry(ioapic: &mut IoApic, irq: IrqVector, vector: InterruptIndex) {
-    let lapic = lapic();
-    let mut entry = RedirectionTableEntry::default();
-    entry.set_mode(IrqMode::Fixed);
-    entry.set_dest(unsafe { lapic.id() } as u8);
-    entry.set_vector(vector as u8);
-    unsafe {
-        ioapic.set_table_entry(irq as u8, entry);
-        ioapic.enable_irq(irq as u8);
+    let (gsi, flags) = isa_irq(IrqVector::Keyboard as u8);
+    let keyboard = irq::register(
+        gsi,
+        crate::interrupt::keyboard_interrupt,
+        core::ptr::null_mut(),
+        flags,
+    );
+    if let Err(e) = keyboard {
+        println!("Couldn't route the keyboard interrupt: {e:?}");
     }
+
+    interrupts::enable();
 }
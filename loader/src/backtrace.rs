@@ -0,0 +1,143 @@
+//! Stack backtraces for panics and fatal exceptions.
+//!
+//! Everything is built with frame pointers, so each frame starts with the caller's `rbp` followed
+//! by the return address, and the chain ends at the zeroed `rbp` of the entry points. Addresses are
+//! named after the function containing them, from the loader's or a module's symbol table. These
+//! are copies of `.symtab` made at load time: the resolver only looks symbols up by name, and only
+//! sees the exported ones in `.dynsym`, which leaves out most functions. Frames are checked before
+//! they are read, since the stack may well be what went wrong; unwinding with `.eh_frame` instead
+//! would also cover code built without frame pointers.
+
+use core::ffi::CStr;
+
+use x86_64::VirtAddr;
+
+use crate::{boot_info::try_boot_info, loader, memory::virt, module, prelude::*};
+
+/// Deeper stacks are cut off, which also stops a walk around a corrupted, circular chain.
+const MAX_FRAMES: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Frame {
+    rbp: u64,
+    return_address: u64,
+}
+
+/// Prints `addr` as `object!function+offset`, or as much of that as is known. Return addresses
+/// point past the call, which may be the start of the next function, so they are looked up one byte
+/// earlier.
+fn print_location(depth: usize, addr: u64, is_return_address: bool) {
+    print!("  #{depth:<2} {addr:#018x}");
+    let adjust = is_return_address as u64;
+    let lookup = VirtAddr::new_truncate(addr.saturating_sub(adjust));
+
+    // A panic while the boot information is being captured must not panic again here
+    let info = try_boot_info();
+    let loader_base = ld_so_impl::load_addr() as u64;
+    let loader_size = info
+        .and_then(|info| loader::image_size(&info.loader_phdrs))
+        .unwrap_or(0) as u64;
+    if let Some(info) = info
+        && (loader_base..loader_base + loader_size).contains(&lookup.as_u64())
+    {
+        let offset = lookup.as_u64() - loader_base;
+        let symbol = info
+            .loader_symbols
+            .as_ref()
+            .and_then(|symbols| symbols.lookup(offset));
+        print_symbol(c"lilium-loader.so", offset + adjust, symbol, adjust);
+        return;
+    }
+
+    let found = module::with_module_at(lookup, |module| {
+        let offset = lookup.as_u64() - module.base.unwrap().as_u64();
+        let symbol = module
+            .symbols
+            .as_ref()
+            .and_then(|symbols| symbols.lookup(offset));
+        print_symbol(module.name(), offset + adjust, symbol, adjust);
+    });
+    if found.is_none() {
+        println!();
+    }
+}
+
+fn print_symbol(object: &CStr, offset: u64, symbol: Option<(&CStr, u64)>, adjust: u64) {
+    match symbol {
+        Some((name, within)) => println!(
+            "  {}!{}+{:#x}",
+            object.display(),
+            name.display(),
+            within + adjust
+        ),
+        None => println!("  {}+{offset:#x}", object.display()),
+    }
+}
+
+/// Why the walk can't continue to the frame at `rbp`, if it can't.
+fn check_frame(rbp: u64, previous: u64) -> Result<(), &'static str> {
+    let Ok(addr) = VirtAddr::try_new(rbp) else {
+        return Err("frame pointer is not canonical");
+    };
+    if !addr.is_aligned(8u64) {
+        return Err("frame pointer is misaligned");
+    }
+    // Callers' frames are always above their callees'
+    if rbp <= previous {
+        return Err("frame pointer does not move up the stack");
+    }
+    let end = addr + (size_of::<Frame>() - 1) as u64;
+    // If the page tables are busy, the other checks will have to do
+    if virt::is_mapped(addr) == Some(false) || virt::is_mapped(end) == Some(false) {
+        return Err("frame pointer is not mapped");
+    }
+    Ok(())
+}
+
+/// Prints a backtrace starting at the instruction at `rip`, with `rbp` the frame pointer of the
+/// function it is in.
+pub fn print_from(rip: u64, rbp: u64) {
+    walk(rip, false, rbp);
+}
+
+/// Prints a backtrace starting at `first`, which is a return address if `is_return_address` is set,
+/// and continuing with the frames from `rbp` up.
+fn walk(first: u64, is_return_address: bool, mut rbp: u64) {
+    println!("Backtrace:");
+    print_location(0, first, is_return_address);
+    let mut previous = 0;
+    for depth in 1..MAX_FRAMES {
+        if rbp == 0 {
+            return;
+        }
+        if let Err(reason) = check_frame(rbp, previous) {
+            println!("  (stopped at {rbp:#018x}: {reason})");
+            return;
+        }
+        let frame = unsafe { (rbp as *const Frame).read() };
+        if frame.return_address == 0 {
+            return;
+        }
+        print_location(depth, frame.return_address, true);
+        previous = rbp;
+        rbp = frame.rbp;
+    }
+    println!("  (more frames omitted)");
+}
+
+/// Prints a backtrace of the caller. Used by the `los-api` panic handler.
+#[unsafe(no_mangle)]
+#[inline(never)]
+extern "C" fn print_backtrace() {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    // Start with our caller, whose return address and frame pointer our own frame holds
+    if check_frame(rbp, 0).is_ok() {
+        let frame = unsafe { (rbp as *const Frame).read() };
+        walk(frame.return_address, true, frame.rbp);
+    }
+}
+los_api::export_symbol!(print_backtrace, 1);
@@ -0,0 +1,200 @@
+use alloc::{ffi::CString, string::String, vec::Vec};
+use core::ffi::CStr;
+
+use limine::{file::File, memory_map::EntryType};
+use los_api::{
+    auxv::{GptBootPartition, MbrBootPartition},
+    cmdline::CommandLine,
+};
+
+use crate::{
+    elf::{ElfFile, Phdr, SymbolTable},
+    framebuffer::Framebuffer,
+    limine_requests::{
+        EXECUTABLE_FILE, FRAMEBUFFER_REQUEST, HHDM_REQUEST, MEMORY_MAP_REQUEST, MODULE_REQUEST,
+        RSDP_REQUEST,
+    },
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub length: u64,
+    pub kind: EntryType,
+}
+
+/// A module file loaded by Limine. The file contents live in `EXECUTABLE_AND_MODULES` memory, which
+/// is never reclaimed, so only the path and string need copying.
+pub struct BootModule {
+    pub path: CString,
+    /// The `module_string` given in `limine.conf`
+    pub string: CString,
+    pub data: &'static [u8],
+    /// Whether the module may be loaded, once [`crate::modsig::check_module`] has checked its
+    /// signature
+    pub signature_ok: spin::Once<bool>,
+}
+
+impl BootModule {
+    /// The file name of the module, which is what `DT_NEEDED` entries refer to it by.
+    pub fn name(&self) -> &CStr {
+        let path = self.path.to_bytes_with_nul();
+        let start = path.iter().rposition(|&b| b == b'/').map_or(0, |i| i + 1);
+        CStr::from_bytes_with_nul(&path[start..]).unwrap()
+    }
+}
+
+los_api::cmdline_options! {
+    pub static LOADER_OPTIONS = [
+        OptionSpec::choice(
+            "module-sig",
+            "enforce|warn",
+            "Refuse modules whose signature is missing or bad, or warn and load them (default)",
+        ),
+        OptionSpec::choice(
+            "module-kaslr",
+            "on|off",
+            "Randomize module base addresses (default), or load them at the lowest free address",
+        ),
+        OptionSpec::choice(
+            "clocksource",
+            "tsc|hpet|pit",
+            "Read the time from this counter, instead of the first of invariant TSC, HPET and PIT",
+        ),
+        OptionSpec::bool("help", "List the options the loader and modules understand"),
+    ];
+}
+
+/// The partition the loader was booted from, for the kernel's auxiliary vector.
+#[derive(Clone, Copy, Debug)]
+pub enum BootPartition {
+    Gpt(GptBootPartition),
+    Mbr(MbrBootPartition),
+}
+
+impl BootPartition {
+    fn of(file: &File) -> Option<Self> {
+        let guid = |uuid: limine::file::Uuid| {
+            let mut bytes = [0; 16];
+            bytes[..4].copy_from_slice(&uuid.a.to_le_bytes());
+            bytes[4..6].copy_from_slice(&uuid.b.to_le_bytes());
+            bytes[6..8].copy_from_slice(&uuid.c.to_le_bytes());
+            bytes[8..].copy_from_slice(&uuid.d);
+            bytes
+        };
+        let disk_guid = guid(file.gpt_disk_id());
+        if disk_guid != [0; 16] {
+            Some(Self::Gpt(GptBootPartition {
+                disk_guid,
+                partition_guid: guid(file.gpt_partition_id()),
+                partition_index: file.partition_index(),
+            }))
+        } else if file.mbr_disk_id() != 0 {
+            Some(Self::Mbr(MbrBootPartition {
+                disk_id: file.mbr_disk_id(),
+                partition_index: file.partition_index(),
+            }))
+        } else {
+            None
+        }
+    }
+}
+
+/// Everything the loader needs from the Limine responses, copied into memory the loader owns.
+///
+/// Limine places its responses in `BOOTLOADER_RECLAIMABLE` memory, which is handed to the frame
+/// allocator by [`crate::memory::reclaim_boot_memory`]. Anything read from a `limine_requests`
+/// request after that point must be captured here first.
+pub struct BootInfo {
+    pub hhdm_offset: u64,
+    pub memory_map: Vec<MemoryRegion>,
+    pub framebuffer: Framebuffer,
+    pub rsdp: Option<usize>,
+    pub modules: Vec<BootModule>,
+    /// The `kernel_cmdline` from `limine.conf`
+    pub cmdline: String,
+    /// The program headers of the loader's own file, which Limine keeps in reclaimable memory
+    pub loader_phdrs: Vec<Phdr>,
+    /// The loader's own functions, for backtraces. Copied for the same reason as `loader_phdrs`.
+    pub loader_symbols: Option<SymbolTable>,
+    /// Unknown when booted over the network, or from a disk without a partition table
+    pub boot_partition: Option<BootPartition>,
+}
+
+impl BootInfo {
+    /// The kernel command line, interpreted with the options the loader declares.
+    pub fn options(&self) -> CommandLine<'_> {
+        CommandLine::new(&self.cmdline, &LOADER_OPTIONS)
+    }
+}
+
+static BOOT_INFO: spin::Once<BootInfo> = spin::Once::new();
+
+/// Copies the Limine responses into [`BootInfo`]. Returns `None` if a response the loader cannot
+/// run without is missing.
+pub fn capture() -> Option<&'static BootInfo> {
+    let hhdm_offset = HHDM_REQUEST.get_response()?.offset();
+    let framebuffer = FRAMEBUFFER_REQUEST.get_response()?.framebuffers().next()?;
+    let memory_map = MEMORY_MAP_REQUEST
+        .get_response()?
+        .entries()
+        .iter()
+        .map(|entry| MemoryRegion {
+            base: entry.base,
+            length: entry.length,
+            kind: entry.entry_type,
+        })
+        .collect();
+    let rsdp = RSDP_REQUEST.get_response().map(|rsdp| rsdp.address());
+    let modules = MODULE_REQUEST
+        .get_response()
+        .map(|response| {
+            response
+                .modules()
+                .iter()
+                .map(|file| BootModule {
+                    path: CString::new(file.path()).unwrap(),
+                    string: CString::new(file.string()).unwrap(),
+                    data: unsafe { core::slice::from_raw_parts(file.addr(), file.size() as usize) },
+                    signature_ok: spin::Once::new(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let executable = EXECUTABLE_FILE.get_response()?.file();
+    let cmdline = String::from_utf8_lossy(executable.string()).into_owned();
+    let loader_elf = ElfFile::parse(unsafe {
+        core::slice::from_raw_parts(executable.addr(), executable.size() as usize)
+    })?;
+    let loader_phdrs = loader_elf.phdrs()?;
+    let loader_symbols = loader_elf.symbol_table();
+    let boot_partition = BootPartition::of(executable);
+
+    Some(BOOT_INFO.call_once(|| BootInfo {
+        hhdm_offset,
+        memory_map,
+        framebuffer: Framebuffer::from(framebuffer),
+        rsdp,
+        modules,
+        cmdline,
+        loader_phdrs,
+        loader_symbols,
+        boot_partition,
+    }))
+}
+
+/// Like [`boot_info`], but `None` until [`capture`] has finished, for code that may run before
+/// then, such as the panic handler.
+pub fn try_boot_info() -> Option<&'static BootInfo> {
+    BOOT_INFO.get()
+}
+
+pub fn boot_info() -> &'static BootInfo {
+    BOOT_INFO
+        .get()
+        .expect("boot information accessed before it was captured")
+}
+
+pub fn hhdm_offset() -> u64 {
+    boot_info().hhdm_offset
+}
@@ -0,0 +1,190 @@
+//! The clocksource behind [`los_api::time`].
+//!
+//! The TSC is the cheapest counter to read, but only keeps a constant rate through power states
+//! and frequency changes when CPUID reports it as invariant. Otherwise the HPET's main counter is
+//! used, and failing that PIT channel 0, left free running and extended to 64 bits in software.
+
+use core::ffi::c_void;
+
+use los_api::{cmdline::Value, time::Clocksource, timer::TICK_HZ};
+use spin::{Mutex, Once};
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{boot_info::boot_info, hpet, prelude::*, timer};
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+/// How often PIT channel 0 must be read, in ticks. It wraps around every 55ms. The local APIC timer
+/// measures the gap in one-shot mode, which doesn't depend on the TSC the PIT stands in for
+const PIT_READ_TICKS: u64 = 50;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+struct Clock {
+    source: Clocksource,
+    /// Counter increments per second
+    hz: u64,
+    /// The counter when the clock was set up, which is time 0
+    base: u64,
+}
+
+static CLOCK: Once<Clock> = Once::new();
+
+/// PIT channel 0 as last read, and the counts it has made since it was started.
+struct PitCounter {
+    last: u16,
+    total: u64,
+}
+
+/// Only locked with interrupts disabled, as the tick callback reads it too
+static PIT_COUNTER: Mutex<PitCounter> = Mutex::new(PitCounter { last: 0, total: 0 });
+
+fn read_pit() -> u16 {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0);
+    unsafe {
+        // Latch channel 0's count, so the two bytes belong together
+        command.write(0b0000_0000);
+        let low = channel_0.read();
+        let high = channel_0.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// Counts PIT channel 0 has made since [`start_pit`]. The channel wraps around every 55ms, so this
+/// must run at least that often; the tick callback sees to it, in one-shot mode too.
+fn pit_counter() -> u64 {
+    interrupts::without_interrupts(|| {
+        let mut counter = PIT_COUNTER.lock();
+        let now = read_pit();
+        // The channel counts down
+        counter.total += counter.last.wrapping_sub(now) as u64;
+        counter.last = now;
+        counter.total
+    })
+}
+
+unsafe extern "C" fn pit_tick(_: u64, _: *mut c_void) {
+    pit_counter();
+}
+
+/// Starts PIT channel 0 counting down from 65536 over and over, and keeps track of it on each tick.
+fn start_pit() {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0);
+    interrupts::without_interrupts(|| unsafe {
+        // Channel 0, low then high byte, mode 2 (rate generator). Its interrupt stays masked in
+        // the I/O APIC
+        command.write(0b0011_0100);
+        channel_0.write(0);
+        channel_0.write(0);
+        PIT_COUNTER.lock().last = read_pit();
+    });
+    timer::register(pit_tick, core::ptr::null_mut());
+    timer::limit_oneshot(PIT_READ_TICKS);
+}
+
+unsafe extern "C" fn hpet_tick(_: u64, _: *mut c_void) {
+    hpet::hpet().unwrap().counter();
+}
+
+/// Has a 32-bit HPET main counter read on ticks, often enough for [`hpet::Hpet::counter`] to see
+/// every wraparound.
+fn start_hpet() {
+    let hpet = hpet::hpet().unwrap();
+    if hpet.counter_64() {
+        return;
+    }
+    timer::register(hpet_tick, core::ptr::null_mut());
+    // Twice per wraparound, to leave room for the timer's and the callbacks' own delays
+    let wrap_ticks = (1u128 << 32) * TICK_HZ as u128 / hpet.frequency() as u128;
+    timer::limit_oneshot((wrap_ticks / 2).max(1) as u64);
+}
+
+fn counter(source: Clocksource) -> u64 {
+    match source {
+        Clocksource::Tsc => timer::rdtsc(),
+        Clocksource::Hpet => hpet::hpet().unwrap().counter(),
+        _ => pit_counter(),
+    }
+}
+
+/// The clocksource `--clocksource` asks for, if any.
+fn requested() -> Option<Clocksource> {
+    match boot_info().options().get("clocksource") {
+        Some(Ok(Value::Enum("tsc"))) => Some(Clocksource::Tsc),
+        Some(Ok(Value::Enum("hpet"))) => Some(Clocksource::Hpet),
+        Some(Ok(Value::Enum("pit"))) => Some(Clocksource::Pit),
+        Some(Ok(_)) | None => None,
+        Some(Err(e)) => {
+            println!("{e}, picking a clocksource automatically");
+            None
+        }
+    }
+}
+
+/// Picks and starts the clocksource. The timer must be calibrated and the HPET found first.
+pub fn init() {
+    CLOCK.call_once(|| {
+        let invariant = timer::invariant_tsc();
+        let source = match requested() {
+            Some(Clocksource::Hpet) if hpet::hpet().is_none() => {
+                println!("There is no HPET to use as the clocksource");
+                None
+            }
+            Some(Clocksource::Tsc) if !invariant => {
+                println!("Using the TSC as the clocksource, though it isn't invariant");
+                Some(Clocksource::Tsc)
+            }
+            requested => requested,
+        }
+        .unwrap_or(if invariant {
+            Clocksource::Tsc
+        } else if hpet::hpet().is_some() {
+            Clocksource::Hpet
+        } else {
+            Clocksource::Pit
+        });
+        let hz = match source {
+            Clocksource::Tsc => timer::tsc_hz(),
+            Clocksource::Hpet => {
+                start_hpet();
+                hpet::hpet().unwrap().frequency()
+            }
+            _ => {
+                start_pit();
+                PIT_HZ
+            }
+        };
+        println!("Clocksource is the {source:?}, at {} kHz", hz / 1000);
+        Clock {
+            source,
+            hz,
+            base: counter(source),
+        }
+    });
+}
+
+/// Nanoseconds since [`init`].
+pub fn monotonic_now() -> u64 {
+    let clock = CLOCK.get().expect("the clocksource hasn't been set up yet");
+    let elapsed = counter(clock.source).saturating_sub(clock.base);
+    (elapsed as u128 * NANOS_PER_SECOND / clock.hz as u128) as u64
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_monotonic_now() -> u64 {
+    monotonic_now()
+}
+los_api::export_symbol!(kernel_monotonic_now, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_clocksource() -> Clocksource {
+    CLOCK
+        .get()
+        .expect("the clocksource hasn't been set up yet")
+        .source
+}
+los_api::export_symbol!(kernel_clocksource, 1);
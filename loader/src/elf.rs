@@ -0,0 +1,343 @@
+//! The parts of the ELF64 format the loader needs to place module images itself, before handing
+//! them to the dynamic linker.
+
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use bytemuck::{Pod, Zeroable};
+
+pub const ELFMAG: [u8; 4] = *b"\x7fELF";
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const ET_DYN: u16 = 3;
+pub const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_DYNAMIC: u32 = 6;
+pub const SHT_DYNSYM: u32 = 11;
+
+pub const SHN_UNDEF: u16 = 0;
+
+pub const STB_WEAK: u8 = 2;
+
+pub const STT_FUNC: u8 = 2;
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+
+pub const DT_NULL: i64 = 0;
+pub const DT_NEEDED: i64 = 1;
+pub const DT_INIT: i64 = 12;
+pub const DT_FINI: i64 = 13;
+pub const DT_INIT_ARRAY: i64 = 25;
+pub const DT_FINI_ARRAY: i64 = 26;
+pub const DT_INIT_ARRAYSZ: i64 = 27;
+pub const DT_FINI_ARRAYSZ: i64 = 28;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+/// Same layout as [`ld_so_impl::elf::ElfPhdr`] on 64-bit targets.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Shdr {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u64,
+    pub sh_entsize: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Sym {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Rela {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
+impl Rela {
+    pub fn sym(&self) -> u32 {
+        (self.r_info >> 32) as u32
+    }
+
+    pub fn kind(&self) -> u32 {
+        self.r_info as u32
+    }
+}
+
+impl Sym {
+    pub fn binding(&self) -> u8 {
+        self.st_info >> 4
+    }
+
+    pub fn kind(&self) -> u8 {
+        self.st_info & 0xf
+    }
+}
+
+impl Phdr {
+    /// Reinterprets the program headers handed to a [`ld_so_impl::loader::LoaderImpl`].
+    pub fn from_ld_so(phdrs: &[ld_so_impl::elf::ElfPhdr]) -> &[Phdr] {
+        const {
+            assert!(size_of::<ld_so_impl::elf::ElfPhdr>() == size_of::<Phdr>());
+        }
+        unsafe { core::slice::from_raw_parts(phdrs.as_ptr().cast(), phdrs.len()) }
+    }
+}
+
+/// A shared object for this machine, borrowed from the bytes of its file.
+pub struct ElfFile<'a> {
+    pub data: &'a [u8],
+    pub ehdr: Ehdr,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let ehdr: Ehdr = bytemuck::try_pod_read_unaligned(data.get(..size_of::<Ehdr>())?).ok()?;
+        if ehdr.e_ident[..4] != ELFMAG
+            || ehdr.e_ident[4] != ELFCLASS64
+            || ehdr.e_ident[5] != ELFDATA2LSB
+            || ehdr.e_type != ET_DYN
+            || ehdr.e_machine != EM_X86_64
+            || ehdr.e_phentsize as usize != size_of::<Phdr>()
+        {
+            return None;
+        }
+        Some(Self { data, ehdr })
+    }
+
+    /// Reads `count` consecutive `T`s starting `offset` bytes into the file.
+    fn table<T: Pod>(&self, offset: u64, count: usize) -> Option<Vec<T>> {
+        let start = offset as usize;
+        let len = count.checked_mul(size_of::<T>())?;
+        let bytes = self.data.get(start..start.checked_add(len)?)?;
+        Some(
+            bytes
+                .chunks_exact(size_of::<T>())
+                .map(bytemuck::pod_read_unaligned)
+                .collect(),
+        )
+    }
+
+    pub fn phdrs(&self) -> Option<Vec<Phdr>> {
+        self.table(self.ehdr.e_phoff, self.ehdr.e_phnum as usize)
+    }
+
+    /// The section headers, or an empty list if the file has been stripped of them.
+    pub fn sections(&self) -> Vec<Shdr> {
+        if self.ehdr.e_shentsize as usize != size_of::<Shdr>() {
+            return Vec::new();
+        }
+        self.table(self.ehdr.e_shoff, self.ehdr.e_shnum as usize)
+            .unwrap_or_default()
+    }
+
+    pub fn section_data(&self, shdr: &Shdr) -> Option<&'a [u8]> {
+        let start = shdr.sh_offset as usize;
+        self.data
+            .get(start..start.checked_add(shdr.sh_size as usize)?)
+    }
+
+    pub fn section_by_name(&self, name: &CStr) -> Option<&'a [u8]> {
+        let sections = self.sections();
+        let shstrtab = self.section_data(sections.get(self.ehdr.e_shstrndx as usize)?)?;
+        let shdr = sections
+            .iter()
+            .find(|shdr| string_at(shstrtab, shdr.sh_name as u64) == Some(name))?;
+        self.section_data(shdr)
+    }
+
+    /// Reads the section `name` as an array of `T`s, such as the tables `los-api` macros place in
+    /// their own sections. Returns an empty list if there is no such section.
+    ///
+    /// # Safety
+    /// Every bit pattern must be a valid `T`.
+    pub unsafe fn section_entries<T: Copy>(&self, name: &CStr) -> Vec<T> {
+        let Some(bytes) = self.section_by_name(name) else {
+            return Vec::new();
+        };
+        bytes
+            .chunks_exact(size_of::<T>())
+            .map(|entry| unsafe { entry.as_ptr().cast::<T>().read_unaligned() })
+            .collect()
+    }
+
+    /// Reads the entries of the first section of type `sh_type`, along with the contents of the
+    /// string table it links to.
+    fn linked_table<T: Pod>(&self, sh_type: u32) -> Option<(Vec<T>, &'a [u8])> {
+        let sections = self.sections();
+        let shdr = sections.iter().find(|shdr| shdr.sh_type == sh_type)?;
+        let strtab = self.section_data(sections.get(shdr.sh_link as usize)?)?;
+        let entries = self.table(shdr.sh_offset, shdr.sh_size as usize / size_of::<T>())?;
+        Some((entries, strtab))
+    }
+
+    /// The names of the shared objects this one depends on, in `DT_NEEDED` order.
+    pub fn needed(&self) -> Vec<&'a CStr> {
+        let Some((dynamic, strtab)) = self.linked_table::<Dyn>(SHT_DYNAMIC) else {
+            return Vec::new();
+        };
+        dynamic
+            .iter()
+            .take_while(|entry| entry.d_tag != DT_NULL)
+            .filter(|entry| entry.d_tag == DT_NEEDED)
+            .filter_map(|entry| string_at(strtab, entry.d_val))
+            .collect()
+    }
+
+    /// The value of the first dynamic entry with tag `tag`.
+    pub fn dynamic_entry(&self, tag: i64) -> Option<u64> {
+        let (dynamic, _) = self.linked_table::<Dyn>(SHT_DYNAMIC)?;
+        dynamic
+            .iter()
+            .take_while(|entry| entry.d_tag != DT_NULL)
+            .find(|entry| entry.d_tag == tag)
+            .map(|entry| entry.d_val)
+    }
+
+    /// Names of the symbols this object imports from others. Weak references, which may be left
+    /// unresolved, aren't included.
+    pub fn imports(&self) -> Vec<&'a CStr> {
+        let Some((symbols, strtab)) = self.linked_table::<Sym>(SHT_DYNSYM) else {
+            return Vec::new();
+        };
+        symbols
+            .iter()
+            .filter(|sym| {
+                sym.st_shndx == SHN_UNDEF && sym.st_name != 0 && sym.binding() != STB_WEAK
+            })
+            .filter_map(|sym| string_at(strtab, sym.st_name as u64))
+            .collect()
+    }
+
+    /// The dynamic relocations that refer to a symbol, from every `SHT_RELA` section linked to the
+    /// dynamic symbol table (`.rela.dyn` and `.rela.plt`).
+    pub fn symbol_relocations(&self) -> Vec<Rela> {
+        let sections = self.sections();
+        let Some(dynsym) = sections.iter().position(|shdr| shdr.sh_type == SHT_DYNSYM) else {
+            return Vec::new();
+        };
+        sections
+            .iter()
+            .filter(|shdr| shdr.sh_type == SHT_RELA && shdr.sh_link as usize == dynsym)
+            .filter_map(|shdr| {
+                self.table::<Rela>(shdr.sh_offset, shdr.sh_size as usize / size_of::<Rela>())
+            })
+            .flatten()
+            .filter(|rela| rela.sym() != 0)
+            .collect()
+    }
+
+    /// Looks up a symbol defined by this object in its dynamic symbol table.
+    pub fn symbol(&self, name: &CStr) -> Option<Sym> {
+        let (symbols, strtab) = self.linked_table::<Sym>(SHT_DYNSYM)?;
+        symbols.into_iter().find(|sym| {
+            sym.st_shndx != SHN_UNDEF && string_at(strtab, sym.st_name as u64) == Some(name)
+        })
+    }
+
+    /// The functions this object defines, from `.symtab` if it hasn't been stripped, otherwise
+    /// from `.dynsym`.
+    pub fn symbol_table(&self) -> Option<SymbolTable> {
+        let (symbols, strtab) = self
+            .linked_table::<Sym>(SHT_SYMTAB)
+            .or_else(|| self.linked_table::<Sym>(SHT_DYNSYM))?;
+        let mut symbols: Vec<Sym> = symbols
+            .into_iter()
+            .filter(|sym| sym.kind() == STT_FUNC && sym.st_shndx != SHN_UNDEF && sym.st_name != 0)
+            .collect();
+        symbols.sort_unstable_by_key(|sym| sym.st_value);
+        Some(SymbolTable {
+            symbols,
+            strtab: strtab.to_vec(),
+        })
+    }
+}
+
+/// Function symbols sorted by address, copied out of an object's file so they outlive it.
+pub struct SymbolTable {
+    symbols: Vec<Sym>,
+    strtab: Vec<u8>,
+}
+
+impl SymbolTable {
+    /// The function containing `offset` from the object's base, and how far into it `offset` is.
+    pub fn lookup(&self, offset: u64) -> Option<(&CStr, u64)> {
+        let idx = self
+            .symbols
+            .partition_point(|sym| sym.st_value <= offset)
+            .checked_sub(1)?;
+        let sym = &self.symbols[idx];
+        let within = offset - sym.st_value;
+        if sym.st_size != 0 && within >= sym.st_size {
+            return None;
+        }
+        Some((string_at(&self.strtab, sym.st_name as u64)?, within))
+    }
+}
+
+pub fn string_at(strtab: &[u8], offset: u64) -> Option<&CStr> {
+    CStr::from_bytes_until_nul(strtab.get(offset as usize..)?).ok()
+}
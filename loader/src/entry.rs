@@ -3,17 +3,20 @@ use core::cell::SyncUnsafeCell;
 use embedded_term::ConsoleOnGraphic;
 use limine::memory_map::EntryType;
 use los_api::{hcf, println};
+use x86_64::VirtAddr;
 
 use crate::{
-    CONSOLE, RESOLVER,
-    framebuffer::Framebuffer,
-    limine_requests::{FRAMEBUFFER_REQUEST, HHDM_REQUEST, MEMORY_MAP_REQUEST},
-    loader::RawPageLoader,
+    CONSOLE, RESOLVER, boot_info, handoff,
+    loader::{self, RawPageLoader},
+    memory, module,
 };
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::{enter_kernel, fill_init_buffer};
+
 const STACK_SIZE: usize = 0x10000;
 
 #[repr(C, align(4096))]
@@ -28,26 +31,17 @@ const INTR_STACK_SIZE: usize = 0x1000;
 pub fn portable_entry(postinit_cb: impl FnOnce()) -> ! {
     let base_addr = ld_so_impl::load_addr();
 
-    let Some(hhdm_response) = HHDM_REQUEST.get_response() else {
+    let Some(boot_info) = boot_info::capture() else {
         hcf()
     };
+    memory::wx::enable_nx();
 
-    let Some(framebuffer_response) = FRAMEBUFFER_REQUEST.get_response() else {
-        hcf();
-    };
-    let Some(framebuffer) = framebuffer_response.framebuffers().next() else {
-        hcf();
-    };
-    let framebuffer = Framebuffer::from(framebuffer);
-    let console = ConsoleOnGraphic::on_frame_buffer(framebuffer);
+    let console = ConsoleOnGraphic::on_frame_buffer(boot_info.framebuffer);
     CONSOLE.call_once(|| spin::Mutex::new(console));
     println!("Hello, world!");
 
-    let Some(memory_map_response) = MEMORY_MAP_REQUEST.get_response() else {
-        hcf();
-    };
-    for entry in memory_map_response.entries() {
-        let entry_type = entry.entry_type;
+    for entry in &boot_info.memory_map {
+        let entry_type = entry.kind;
         let base = entry.base;
         let length = entry.length;
         let entry_type_str = match entry_type {
@@ -72,10 +66,38 @@ pub fn portable_entry(postinit_cb: impl FnOnce()) -> ! {
         );
     }
 
+    let frame_stats = memory::phys::stats();
+    println!(
+        "\x1b[0mPhysical memory: {} KiB free of {} KiB",
+        frame_stats.free_frames * 4,
+        frame_stats.total_frames * 4
+    );
+
     println!("Base Address: {base_addr:p}");
 
     postinit_cb();
 
+    unsafe {
+        memory::reclaim_boot_memory();
+    }
+
+    let frame_stats = memory::phys::stats();
+    println!(
+        "Reclaimed boot memory, {} KiB free of {} KiB",
+        frame_stats.free_frames * 4,
+        frame_stats.total_frames * 4
+    );
+
+    let heap = memory::heap::ALLOCATOR.mapped_range();
+    println!(
+        "Heap mapped with {}",
+        memory::virt::summarize(VirtAddr::new(heap.start as u64), heap.len())
+    );
+
+    unsafe {
+        memory::wx::protect_loader(VirtAddr::from_ptr(base_addr), &boot_info.loader_phdrs);
+    }
+
     let dyn_ent = ld_so_impl::dynamic_section();
 
     println!("Calling Dynamic Loader");
@@ -93,7 +115,28 @@ pub fn portable_entry(postinit_cb: impl FnOnce()) -> ! {
         );
     }
 
+    unsafe {
+        loader::protect_relro(base_addr as usize, &boot_info.loader_phdrs)
+            .expect("failed to protect loader RELRO");
+    }
+
     println!("Dynloader loaded");
 
-    hcf()
+    module::load_all();
+    module::check_cmdline();
+
+    memory::wx::harden_hhdm();
+    match memory::wx::report_wx_mappings() {
+        0 => println!("W^X check passed"),
+        count => println!("W^X check found {count} writable and executable ranges"),
+    }
+
+    module::init_all();
+
+    let Some((kernel, base)) = module::kernel_module() else {
+        println!("No kernel module to start: none is marked `kernel`, or it failed");
+        hcf()
+    };
+    println!("Starting {}", kernel.name().display());
+    unsafe { handoff::start_kernel(kernel, base) }
 }
@@ -17,6 +17,7 @@ use crate::{
     entry::{INTR_STACK_SIZE, PageAlign, STACK, STACK_SIZE},
     interrupt::IDT,
     limine_requests::BASE_REVISION,
+    memory,
 };
 
 #[unsafe(link_section = ".bss.stack")]
@@ -43,6 +44,8 @@ unsafe extern "C" fn kmain() -> ! {
         "mov rax, cr4",
         "or rax, {CR4}",
         "mov cr4, rax",
+        // Ends the frame pointer chain for backtraces
+        "xor ebp, ebp",
         "call {kmain_real}",
         "jmp {hcf}",
         STACK = sym STACK,
@@ -119,6 +122,8 @@ extern "C" fn kmain_real() -> ! {
 
     IDT.load();
 
+    memory::virt::init_pat();
+
     super::portable_entry(|| {
         apic::init();
     })
@@ -133,6 +138,30 @@ extern "C" fn hcf_real() -> ! {
         }
     }
 }
+los_api::export_symbol!(hcf_real, 1);
+
+/// Switches to `stack` and jumps to the kernel's `_start`, which expects `rsp` to point at `argc`.
+/// Setting bit 0 of `eax` tells it `envp` and `auxv` are passed in `r12` and `rbx`, so it needn't
+/// search for them.
+///
+/// # Safety
+/// `stack` must hold a complete initial stack, and `entry` must be the kernel's entry point.
+pub unsafe fn enter_kernel(entry: usize, stack: usize, envp: usize, auxv: usize) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, rdx",
+            "mov rbx, rsi",
+            "xor ebp, ebp",
+            "mov eax, 1",
+            "jmp rcx",
+            in("rcx") entry,
+            in("rdx") stack,
+            in("rsi") auxv,
+            in("r12") envp,
+            options(noreturn),
+        )
+    }
+}
 
 pub fn fill_init_buffer<F: FnOnce(&[u8])>(f: F) {
     let mut init_buf = [0u8; 16];
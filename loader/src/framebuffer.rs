@@ -5,22 +5,69 @@ use embedded_graphics_core::{
     prelude::{OriginDimensions, RgbColor, Size},
 };
 use limine::framebuffer::Framebuffer as LimineFramebuffer;
+use los_api::auxv::FramebufferInfo;
 
 pub enum Error {
     OutOfBounds,
 }
 
-pub struct Framebuffer<'a> {
-    inner: LimineFramebuffer<'a>,
+/// An owned copy of the geometry of a Limine framebuffer, so that it outlives the Limine response
+/// it was read from.
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    addr: *mut u8,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
 }
 
-impl<'a> Framebuffer<'a> {
-    pub fn new(inner: LimineFramebuffer<'a>) -> Self {
-        Self { inner }
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    pub fn new(inner: &LimineFramebuffer) -> Self {
+        Self {
+            addr: inner.addr(),
+            width: inner.width(),
+            height: inner.height(),
+            pitch: inner.pitch(),
+            bpp: inner.bpp(),
+            red_mask_size: inner.red_mask_size(),
+            red_mask_shift: inner.red_mask_shift(),
+            green_mask_size: inner.green_mask_size(),
+            green_mask_shift: inner.green_mask_shift(),
+            blue_mask_size: inner.blue_mask_size(),
+            blue_mask_shift: inner.blue_mask_shift(),
+        }
+    }
+
+    /// The geometry and format as handed to the kernel, given the HHDM offset the framebuffer is
+    /// mapped at.
+    pub fn info(&self, hhdm_offset: u64) -> FramebufferInfo {
+        FramebufferInfo {
+            address: self.addr,
+            phys_address: self.addr as u64 - hhdm_offset,
+            width: self.width,
+            height: self.height,
+            pitch: self.pitch,
+            bpp: self.bpp,
+            red_mask_size: self.red_mask_size,
+            red_mask_shift: self.red_mask_shift,
+            green_mask_size: self.green_mask_size,
+            green_mask_shift: self.green_mask_shift,
+            blue_mask_size: self.blue_mask_size,
+            blue_mask_shift: self.blue_mask_shift,
+        }
     }
 }
 
-impl<'a> DrawTarget for Framebuffer<'a> {
+impl DrawTarget for Framebuffer {
     type Color = Rgb888;
     type Error = Error;
 
@@ -28,22 +75,21 @@ impl<'a> DrawTarget for Framebuffer<'a> {
     where
         I: IntoIterator<Item = Pixel<Rgb888>>,
     {
-        let x_scale = self.inner.bpp() as usize / 8;
-        let y_scale = self.inner.pitch() as usize;
+        let x_scale = self.bpp as usize / 8;
+        let y_scale = self.pitch as usize;
         for Pixel(point, color) in pixels {
             if point.x < 0
                 || point.y < 0
-                || point.x as u64 >= self.inner.width()
-                || point.y as u64 >= self.inner.height()
+                || point.x as u64 >= self.width
+                || point.y as u64 >= self.height
             {
                 Err(Error::OutOfBounds)?;
             }
-            let color_u32 = ((color.r() as u32) << self.inner.red_mask_shift())
-                | ((color.g() as u32) << self.inner.green_mask_shift())
-                | ((color.b() as u32) << self.inner.blue_mask_shift());
+            let color_u32 = ((color.r() as u32) << self.red_mask_shift)
+                | ((color.g() as u32) << self.green_mask_shift)
+                | ((color.b() as u32) << self.blue_mask_shift);
             unsafe {
-                self.inner
-                    .addr()
+                self.addr
                     .add(point.x as usize * x_scale as usize + point.y as usize * y_scale as usize)
                     .cast::<u32>()
                     .write(color_u32);
@@ -53,17 +99,17 @@ impl<'a> DrawTarget for Framebuffer<'a> {
     }
 }
 
-impl<'a> OriginDimensions for Framebuffer<'a> {
+impl OriginDimensions for Framebuffer {
     fn size(&self) -> Size {
         Size {
-            width: self.inner.width() as u32,
-            height: self.inner.height() as u32,
+            width: self.width as u32,
+            height: self.height as u32,
         }
     }
 }
 
-impl<'a> From<LimineFramebuffer<'a>> for Framebuffer<'a> {
+impl<'a> From<LimineFramebuffer<'a>> for Framebuffer {
     fn from(inner: LimineFramebuffer<'a>) -> Self {
-        Self::new(inner)
+        Self::new(&inner)
     }
 }
@@ -0,0 +1,209 @@
+//! Handing control to the kernel module: building the initial stack a System V `_start` expects,
+//! then jumping to the kernel's entry point.
+
+use alloc::vec::Vec;
+
+use limine::memory_map::EntryType;
+use los_api::{
+    auxv::{
+        AT_BASE, AT_ENTRY, AT_LILIUM_OS_BOOT_PART_GPT, AT_LILIUM_OS_BOOT_PART_MBR,
+        AT_LILIUM_OS_FRAMEBUFFER, AT_LILIUM_OS_HHDM_OFFSET, AT_LILIUM_OS_MEMORY_MAP,
+        AT_LILIUM_OS_RSDP, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, AT_RANDOM, MemoryKind,
+        MemoryMap, MemoryMapEntry,
+    },
+    mem::{AddressRegion, Protection},
+    rand::CsRand,
+};
+use rand_core::RngCore;
+use x86_64::VirtAddr;
+
+use crate::{
+    boot_info::{BootModule, BootPartition, boot_info},
+    elf::{ElfFile, PT_LOAD, Phdr},
+    entry::{enter_kernel, fill_init_buffer},
+    memory::virt::{self, PAGE_SIZE},
+};
+
+/// Size of the kernel's initial stack, not counting the unmapped guard page below it
+const KERNEL_STACK_SIZE: usize = 0x40000;
+
+/// Fills a stack from the top down.
+struct StackBuilder {
+    sp: usize,
+}
+
+impl StackBuilder {
+    /// Copies `bytes` onto the stack at an address aligned to `align`, returning that address.
+    unsafe fn push_bytes(&mut self, bytes: &[u8], align: usize) -> usize {
+        self.sp = (self.sp - bytes.len()) & !(align - 1);
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.sp as *mut u8, bytes.len());
+        }
+        self.sp
+    }
+
+    unsafe fn push_str(&mut self, s: &str) -> usize {
+        let mut bytes = Vec::with_capacity(s.len() + 1);
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+        unsafe { self.push_bytes(&bytes, 1) }
+    }
+
+    unsafe fn push_slice<T: Copy>(&mut self, values: &[T]) -> usize {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(values.as_ptr().cast::<u8>(), size_of_val(values))
+        };
+        unsafe { self.push_bytes(bytes, align_of::<T>()) }
+    }
+
+    unsafe fn push_value<T: Copy>(&mut self, value: &T) -> usize {
+        unsafe { self.push_slice(core::slice::from_ref(value)) }
+    }
+}
+
+/// Maps a fresh stack in [`AddressRegion::Stacks`], with a guard page below it, and returns its
+/// top.
+fn allocate_stack() -> VirtAddr {
+    let base = virt::reserve(
+        AddressRegion::Stacks,
+        KERNEL_STACK_SIZE + PAGE_SIZE,
+        PAGE_SIZE,
+    )
+    .expect("no address space for the kernel stack");
+    let stack = base + PAGE_SIZE as u64;
+    unsafe { virt::map_anonymous(stack, KERNEL_STACK_SIZE, Protection::WRITE) }
+        .expect("out of memory mapping the kernel stack");
+    stack + KERNEL_STACK_SIZE as u64
+}
+
+fn memory_kind(kind: EntryType) -> MemoryKind {
+    match kind {
+        EntryType::USABLE => MemoryKind::Usable,
+        EntryType::ACPI_RECLAIMABLE => MemoryKind::AcpiReclaimable,
+        EntryType::ACPI_NVS => MemoryKind::AcpiNvs,
+        EntryType::BAD_MEMORY => MemoryKind::BadMemory,
+        EntryType::BOOTLOADER_RECLAIMABLE => MemoryKind::BootloaderReclaimable,
+        EntryType::EXECUTABLE_AND_MODULES => MemoryKind::ExecutableAndModules,
+        EntryType::FRAMEBUFFER => MemoryKind::Framebuffer,
+        _ => MemoryKind::Reserved,
+    }
+}
+
+/// Where the kernel's program headers ended up in memory, if they are part of a loaded segment.
+fn phdr_address(elf: &ElfFile, phdrs: &[Phdr], base: VirtAddr) -> Option<u64> {
+    let phoff = elf.ehdr.e_phoff;
+    phdrs
+        .iter()
+        .filter(|phdr| phdr.p_type == PT_LOAD)
+        .find(|phdr| phoff >= phdr.p_offset && phoff < phdr.p_offset + phdr.p_filesz)
+        .map(|phdr| base.as_u64() + phdr.p_vaddr + (phoff - phdr.p_offset))
+}
+
+#[cfg(target_arch = "x86_64")]
+fn arch_auxv() -> [(usize, usize); 2] {
+    use los_api::auxv::{AT_LILIUM_OS_GDT_BASE, AT_LILIUM_OS_IDT_BASE};
+    use x86_64::instructions::tables::{sgdt, sidt};
+
+    [
+        (AT_LILIUM_OS_GDT_BASE, sgdt().base.as_u64() as usize),
+        (AT_LILIUM_OS_IDT_BASE, sidt().base.as_u64() as usize),
+    ]
+}
+
+/// Builds the kernel's initial stack and jumps to its entry point. `argv` is the file name of the
+/// kernel followed by the words of the kernel command line, and the environment is empty.
+///
+/// # Safety
+/// `kernel` must be loaded and initialized at `base`. Nothing on the current stack is used again.
+pub unsafe fn start_kernel(kernel: &'static BootModule, base: VirtAddr) -> ! {
+    let elf = ElfFile::parse(kernel.data).expect("loaded module is not a valid ELF file");
+    let phdrs = elf.phdrs().expect("loaded module has no program headers");
+    assert!(
+        elf.ehdr.e_entry != 0,
+        "the kernel module has no entry point"
+    );
+    let entry = base.as_u64() + elf.ehdr.e_entry;
+
+    let mut random = [0u8; 16];
+    fill_init_buffer(|seed| CsRand::new(seed).fill_bytes(&mut random));
+
+    let mut stack = StackBuilder {
+        sp: allocate_stack().as_u64() as usize,
+    };
+    let mut argv = Vec::new();
+    let mut auxv = Vec::new();
+    unsafe {
+        argv.push(stack.push_str(&kernel.name().to_string_lossy()));
+        for arg in boot_info().cmdline.split_whitespace() {
+            argv.push(stack.push_str(arg));
+        }
+
+        auxv.push((AT_RANDOM, stack.push_bytes(&random, 16)));
+        match &boot_info().boot_partition {
+            Some(BootPartition::Gpt(part)) => {
+                auxv.push((AT_LILIUM_OS_BOOT_PART_GPT, stack.push_value(part)))
+            }
+            Some(BootPartition::Mbr(part)) => {
+                auxv.push((AT_LILIUM_OS_BOOT_PART_MBR, stack.push_value(part)))
+            }
+            None => {}
+        }
+
+        let entries: Vec<_> = boot_info()
+            .memory_map
+            .iter()
+            .map(|region| MemoryMapEntry {
+                base: region.base,
+                length: region.length,
+                kind: memory_kind(region.kind),
+            })
+            .collect();
+        let memory_map = MemoryMap {
+            entries: stack.push_slice(&entries) as *const MemoryMapEntry,
+            len: entries.len(),
+        };
+        auxv.push((AT_LILIUM_OS_MEMORY_MAP, stack.push_value(&memory_map)));
+
+        let framebuffer = boot_info().framebuffer.info(boot_info().hhdm_offset);
+        auxv.push((AT_LILIUM_OS_FRAMEBUFFER, stack.push_value(&framebuffer)));
+    }
+    if let Some(rsdp) = boot_info().rsdp {
+        auxv.push((AT_LILIUM_OS_RSDP, rsdp));
+    }
+    auxv.extend([
+        (AT_LILIUM_OS_HHDM_OFFSET, boot_info().hhdm_offset as usize),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, ld_so_impl::load_addr() as usize),
+        (AT_ENTRY, entry as usize),
+        (AT_PHENT, size_of::<Phdr>()),
+        (AT_PHNUM, phdrs.len()),
+    ]);
+    if let Some(addr) = phdr_address(&elf, &phdrs, base) {
+        auxv.push((AT_PHDR, addr as usize));
+    }
+    #[cfg(target_arch = "x86_64")]
+    auxv.extend(arch_auxv());
+    auxv.push((AT_NULL, 0));
+
+    // argc, argv, NULL, envp (empty), NULL, then the auxiliary vector
+    let mut words = Vec::with_capacity(argv.len() + 3 + auxv.len() * 2);
+    words.push(argv.len());
+    words.extend(&argv);
+    words.push(0);
+    let envp_index = words.len();
+    words.push(0);
+    let auxv_index = words.len();
+    words.extend(auxv.iter().flat_map(|&(tag, value)| [tag, value]));
+
+    // Aligned so that `rsp` is 16-byte aligned at `_start`, as the ABI requires
+    let sp = unsafe { stack.push_bytes(bytemuck::cast_slice(&words), 16) };
+    let word = size_of::<usize>();
+    unsafe {
+        enter_kernel(
+            entry as usize,
+            sp,
+            sp + envp_index * word,
+            sp + auxv_index * word,
+        )
+    }
+}
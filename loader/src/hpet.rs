@@ -0,0 +1,356 @@
+//! The High Precision Event Timer, found through the ACPI HPET table.
+//!
+//! Its main counter runs at a fixed frequency from the moment it is enabled, which makes it a
+//! reference for calibrating the other timers and a clocksource in its own right. Each comparator
+//! can raise an interrupt when the counter reaches a value, for one-shot events; modules claim them
+//! through [`los_api::hpet`].
+
+use core::{
+    ffi::c_void,
+    ops::Range,
+    sync::atomic::{AtomicU8, AtomicU64, Ordering},
+};
+
+use los_api::{
+    irq::{IrqError, IrqFlags, IrqHandler},
+    mem::CacheMode,
+};
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts};
+
+use crate::{apic, interrupt::irq, memory::virt::map_mmio, prelude::*};
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const fn timer_config(n: u8) -> usize {
+    0x100 + 0x20 * n as usize
+}
+
+const fn timer_comparator(n: u8) -> usize {
+    0x108 + 0x20 * n as usize
+}
+
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TN_INT_LEVEL: u64 = 1 << 1;
+const TN_INT_ENABLE: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_SIZE_64: u64 = 1 << 5;
+const TN_32BIT: u64 = 1 << 8;
+const TN_ROUTE_SHIFT: u32 = 9;
+const TN_ROUTE_MASK: u64 = 0b1_1111 << TN_ROUTE_SHIFT;
+const TN_FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The size of the register block
+const MMIO_SIZE: usize = 0x400;
+
+pub struct Hpet {
+    base: VirtAddr,
+    /// Main counter frequency
+    frequency: u64,
+    comparators: u8,
+    counter_64: bool,
+    /// Comparators handed out by [`Hpet::claim_comparator`], one bit each
+    claimed: AtomicU8,
+    /// The last value of a 32-bit main counter, extended to 64 bits
+    last_counter: AtomicU64,
+}
+
+static HPET: Once<Option<Hpet>> = Once::new();
+
+impl Hpet {
+    unsafe fn read(&self, offset: usize) -> u64 {
+        unsafe { ((self.base.as_u64() as usize + offset) as *const u64).read_volatile() }
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        unsafe { ((self.base.as_u64() as usize + offset) as *mut u64).write_volatile(value) }
+    }
+
+    /// Main counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Whether the main counter is 64 bits wide, rather than 32.
+    pub fn counter_64(&self) -> bool {
+        self.counter_64
+    }
+
+    /// The main counter. A 32-bit counter is extended to 64 bits, which only works as long as it is
+    /// read at least once every wraparound (a few minutes at the usual frequencies).
+    pub fn counter(&self) -> u64 {
+        let raw = unsafe { self.read(MAIN_COUNTER) };
+        if self.counter_64 {
+            return raw;
+        }
+        let raw = raw & u32::MAX as u64;
+        let mut last = self.last_counter.load(Ordering::Relaxed);
+        loop {
+            let mut next = (last & !(u32::MAX as u64)) | raw;
+            if next < last {
+                next += 1 << 32;
+            }
+            match self.last_counter.compare_exchange_weak(
+                last,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    /// Claims a free comparator and routes its interrupt to `handler` through one of the I/O APIC
+    /// inputs it can use. The comparator starts out disarmed.
+    ///
+    /// # Safety
+    /// As for [`los_api::irq::register_irq`].
+    pub unsafe fn claim_comparator(
+        &'static self,
+        handler: IrqHandler,
+        data: *mut c_void,
+    ) -> Result<Comparator, IrqError> {
+        let n = (0..self.comparators)
+            .find(|&n| self.claimed.fetch_or(1 << n, Ordering::Relaxed) & (1 << n) == 0)
+            .ok_or(IrqError::NoFreeVector)?;
+        let config = unsafe { self.read(timer_config(n)) };
+        let routes = config >> 32;
+        // Each comparator is wired to the I/O APIC inputs set in its route capability
+        let mut res = Err(IrqError::NoSuchLine);
+        for gsi in (0..32).filter(|gsi| routes & (1 << gsi) != 0) {
+            res = irq::register(gsi, handler, data, IrqFlags::EDGE);
+            if res.is_ok() {
+                // Edge triggered, as the line was registered
+                let cleared =
+                    TN_ROUTE_MASK | TN_INT_LEVEL | TN_INT_ENABLE | TN_PERIODIC | TN_FSB_ENABLE;
+                let config = (config & !cleared) | ((gsi as u64) << TN_ROUTE_SHIFT);
+                unsafe { self.write(timer_config(n), config) };
+                break;
+            }
+        }
+        match res {
+            Ok((irq_id, _)) => Ok(Comparator {
+                hpet: self,
+                n,
+                irq_id,
+                wide: self.counter_64 && config & TN_SIZE_64 != 0,
+            }),
+            Err(e) => {
+                self.claimed.fetch_and(!(1 << n), Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A comparator claimed with [`Hpet::claim_comparator`]. Dropping it disarms and releases it.
+pub struct Comparator {
+    hpet: &'static Hpet,
+    n: u8,
+    irq_id: u64,
+    /// Whether the comparator is 64 bits wide. A 32-bit one only matches the low half of the
+    /// counter
+    wide: bool,
+}
+
+impl Comparator {
+    /// Raises the comparator's interrupt once the main counter reaches `deadline`, replacing any
+    /// deadline armed before. A deadline that has already passed only fires once the counter wraps
+    /// around, so callers should check the counter afterwards. A 32-bit comparator can't wait more
+    /// than one wraparound, and fires after that long instead.
+    pub fn arm(&self, deadline: u64) {
+        let deadline = if self.wide {
+            deadline
+        } else {
+            deadline.min(self.hpet.counter() + u32::MAX as u64) & u32::MAX as u64
+        };
+        unsafe {
+            let config = self.hpet.read(timer_config(self.n));
+            self.hpet
+                .write(timer_config(self.n), config & !TN_INT_ENABLE);
+            self.hpet.write(timer_comparator(self.n), deadline);
+            self.hpet
+                .write(timer_config(self.n), config | TN_INT_ENABLE);
+        }
+    }
+
+    pub fn disarm(&self) {
+        unsafe {
+            let config = self.hpet.read(timer_config(self.n));
+            self.hpet
+                .write(timer_config(self.n), config & !TN_INT_ENABLE);
+        }
+    }
+}
+
+impl Drop for Comparator {
+    fn drop(&mut self) {
+        self.disarm();
+        irq::unregister(self.irq_id);
+        self.hpet
+            .claimed
+            .fetch_and(!(1 << self.n), Ordering::Relaxed);
+    }
+}
+
+/// A comparator claimed by a module, and the handler it was claimed for
+struct Claimed {
+    comparator: Comparator,
+    handler: IrqHandler,
+}
+
+/// Comparators claimed through [`kernel_claim_hpet_comparator`], by number. Only locked with
+/// interrupts disabled, as handlers may rearm their comparator.
+static CLAIMED: Mutex<[Option<Claimed>; 8]> = Mutex::new([const { None }; 8]);
+
+/// Releases every comparator claimed for a handler whose code lies in `range`, the image of a
+/// module being unloaded. Returns how many there were.
+pub fn release_in(range: Range<u64>) -> usize {
+    interrupts::without_interrupts(|| {
+        CLAIMED
+            .lock()
+            .iter_mut()
+            .filter(|slot| {
+                slot.as_ref()
+                    .is_some_and(|claimed| range.contains(&(claimed.handler as usize as u64)))
+            })
+            .filter_map(Option::take)
+            .count()
+    })
+}
+
+/// Finds the HPET, if there is one, and starts its main counter. Must run before boot memory is
+/// reclaimed.
+pub fn init() {
+    HPET.call_once(|| {
+        let info = apic::hpet_info()?;
+        let base = unsafe {
+            map_mmio(
+                PhysAddr::new(info.base_address as u64),
+                MMIO_SIZE,
+                CacheMode::Uncached,
+            )
+        }
+        .inspect_err(|e| println!("Couldn't map the HPET: {e:?}"))
+        .ok()?;
+        let mut hpet = Hpet {
+            base,
+            frequency: 0,
+            comparators: 0,
+            counter_64: false,
+            claimed: AtomicU8::new(0),
+            last_counter: AtomicU64::new(0),
+        };
+        let capabilities = unsafe { hpet.read(CAPABILITIES) };
+        let period_fs = capabilities >> 32;
+        if period_fs == 0 {
+            println!("Ignoring the HPET: it reports a period of 0");
+            return None;
+        }
+        hpet.frequency = FEMTOSECONDS_PER_SECOND / period_fs;
+        // Only 8 comparators are tracked, which is more than any chipset has
+        hpet.comparators = (((capabilities >> 8) & 0b1_1111) as u8 + 1).min(8);
+        hpet.counter_64 = capabilities & CAP_COUNT_SIZE_64 != 0;
+
+        unsafe {
+            for n in 0..hpet.comparators {
+                let config = hpet.read(timer_config(n));
+                hpet.write(
+                    timer_config(n),
+                    config & !(TN_INT_ENABLE | TN_PERIODIC | TN_32BIT),
+                );
+            }
+            let config = hpet.read(CONFIGURATION);
+            hpet.write(CONFIGURATION, (config & !CONF_LEGACY_ROUTE) | CONF_ENABLE);
+        }
+        println!(
+            "HPET runs at {} kHz with {} comparators and a {}-bit counter",
+            hpet.frequency / 1000,
+            hpet.comparators,
+            if hpet.counter_64 { 64 } else { 32 }
+        );
+        Some(hpet)
+    });
+}
+
+/// The HPET, if the machine has one and [`init`] found it.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()?.as_ref()
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_claim_hpet_comparator(
+    handler: IrqHandler,
+    data: *mut c_void,
+    n: *mut u8,
+) -> u32 {
+    let Some(hpet) = hpet() else {
+        return IrqError::into_status(Err(IrqError::NoSuchLine));
+    };
+    IrqError::into_status(
+        unsafe { hpet.claim_comparator(handler, data) }.map(|comparator| {
+            unsafe {
+                n.write(comparator.n);
+            }
+            interrupts::without_interrupts(|| {
+                CLAIMED.lock()[comparator.n as usize] = Some(Claimed {
+                    comparator,
+                    handler,
+                });
+            });
+        }),
+    )
+}
+los_api::export_symbol!(kernel_claim_hpet_comparator, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_arm_hpet_comparator(n: u8, deadline: u64) {
+    interrupts::without_interrupts(|| {
+        if let Some(Some(claimed)) = CLAIMED.lock().get(n as usize) {
+            claimed.comparator.arm(deadline);
+        }
+    });
+}
+los_api::export_symbol!(kernel_arm_hpet_comparator, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_disarm_hpet_comparator(n: u8) {
+    interrupts::without_interrupts(|| {
+        if let Some(Some(claimed)) = CLAIMED.lock().get(n as usize) {
+            claimed.comparator.disarm();
+        }
+    });
+}
+los_api::export_symbol!(kernel_disarm_hpet_comparator, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_release_hpet_comparator(n: u8) {
+    interrupts::without_interrupts(|| {
+        if let Some(slot) = CLAIMED.lock().get_mut(n as usize) {
+            *slot = None;
+        }
+    });
+}
+los_api::export_symbol!(kernel_release_hpet_comparator, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_hpet_frequency() -> u64 {
+    hpet().map_or(0, Hpet::frequency)
+}
+los_api::export_symbol!(kernel_hpet_frequency, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_hpet_counter() -> u64 {
+    hpet().map_or(0, Hpet::counter)
+}
+los_api::export_symbol!(kernel_hpet_counter, 1);
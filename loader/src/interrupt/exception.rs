@@ -0,0 +1,267 @@
+//! Handlers for the architectural exceptions, which dump the interrupted register state and halt.
+//!
+//! They are built with [`interrupt_handler`] and [`exception_handler`] like any other handler, so
+//! the dump comes from the [`InterruptContext`] those save.
+
+use los_api::arch::x86_64::{
+    InterruptContext, InterruptErrorCode, InterruptResult, exception_handler, interrupt_handler,
+};
+use x86_64::{
+    VirtAddr,
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        rflags::RFlags,
+        segmentation::{DS, ES, FS, GS, Segment},
+    },
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+};
+
+use crate::{backtrace, prelude::*};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ErrorCode {
+    None,
+    /// A segment selector index, for #TS, #NP, #SS and #GP
+    Selector,
+    PageFault,
+    ControlProtection,
+    /// Pushed by the CPU, but always zero or without a defined format
+    Opaque,
+}
+
+struct Exception {
+    mnemonic: &'static str,
+    name: &'static str,
+    error_code: ErrorCode,
+}
+
+const fn exception(mnemonic: &'static str, name: &'static str, error_code: ErrorCode) -> Exception {
+    Exception {
+        mnemonic,
+        name,
+        error_code,
+    }
+}
+
+fn describe(vector: u8) -> Exception {
+    match vector {
+        0 => exception("DE", "divide error", ErrorCode::None),
+        1 => exception("DB", "debug", ErrorCode::None),
+        2 => exception("NMI", "non-maskable interrupt", ErrorCode::None),
+        3 => exception("BP", "breakpoint", ErrorCode::None),
+        4 => exception("OF", "overflow", ErrorCode::None),
+        5 => exception("BR", "bound range exceeded", ErrorCode::None),
+        6 => exception("UD", "invalid opcode", ErrorCode::None),
+        7 => exception("NM", "device not available", ErrorCode::None),
+        8 => exception("DF", "double fault", ErrorCode::Opaque),
+        10 => exception("TS", "invalid TSS", ErrorCode::Selector),
+        11 => exception("NP", "segment not present", ErrorCode::Selector),
+        12 => exception("SS", "stack segment fault", ErrorCode::Selector),
+        13 => exception("GP", "general protection fault", ErrorCode::Selector),
+        14 => exception("PF", "page fault", ErrorCode::PageFault),
+        16 => exception("MF", "x87 floating point exception", ErrorCode::None),
+        17 => exception("AC", "alignment check", ErrorCode::Opaque),
+        18 => exception("MC", "machine check", ErrorCode::None),
+        19 => exception("XM", "SIMD floating point exception", ErrorCode::None),
+        20 => exception("VE", "virtualization exception", ErrorCode::None),
+        21 => exception(
+            "CP",
+            "control protection exception",
+            ErrorCode::ControlProtection,
+        ),
+        28 => exception("HV", "hypervisor injection exception", ErrorCode::None),
+        29 => exception("VC", "VMM communication exception", ErrorCode::Opaque),
+        30 => exception("SX", "security exception", ErrorCode::Opaque),
+        _ => exception("??", "unknown exception", ErrorCode::None),
+    }
+}
+
+/// Decodes the error code of #TS, #NP, #SS and #GP, which refers to a descriptor.
+fn print_selector_error(code: u64) {
+    if code == 0 {
+        println!("  Not caused by a particular descriptor");
+        return;
+    }
+    let table = if code & 0b10 != 0 {
+        "IDT"
+    } else if code & 0b100 != 0 {
+        "LDT"
+    } else {
+        "GDT"
+    };
+    println!(
+        "  Descriptor {table}[{}]{}",
+        (code >> 3) & 0x1fff,
+        if code & 1 != 0 {
+            ", during delivery of an external event"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Decodes the error code of #CP.
+fn print_control_protection_error(code: u64) {
+    let cause = match code & 0x7fff {
+        1 => "near RET",
+        2 => "far RET or IRET",
+        3 => "missing ENDBRANCH",
+        4 => "RSTORSSP",
+        5 => "SETSSBSY",
+        _ => "unknown cause",
+    };
+    println!(
+        "  Caused by {cause}{}",
+        if code & (1 << 15) != 0 {
+            " in an enclave"
+        } else {
+            ""
+        }
+    );
+}
+
+fn print_registers(frame: &InterruptContext) {
+    println!(
+        "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    );
+    println!(
+        "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+        frame.rsi, frame.rdi, frame.rbp, frame.rsp
+    );
+    println!(
+        "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+        frame.r8, frame.r9, frame.r10, frame.r11
+    );
+    println!(
+        "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+        frame.r12, frame.r13, frame.r14, frame.r15
+    );
+    println!(
+        "RIP={:016x} RFLAGS={:016x} {:?}",
+        frame.rip,
+        frame.rflags,
+        RFlags::from_bits_truncate(frame.rflags)
+    );
+    println!(
+        "CS={:04x} SS={:04x} DS={:04x} ES={:04x} FS={:04x} GS={:04x}",
+        frame.cs,
+        frame.ss,
+        DS::get_reg().0,
+        ES::get_reg().0,
+        FS::get_reg().0,
+        GS::get_reg().0
+    );
+    let (l4_frame, pcid) = Cr3::read_raw();
+    println!(
+        "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        l4_frame.start_address().as_u64() | pcid as u64,
+        Cr4::read_raw()
+    );
+}
+
+fn report_exception(vector: u8, frame: &InterruptContext) -> ! {
+    let exception = describe(vector);
+    print!(
+        "\x1b[31;1mCaught #{} ({}) at {:04x}:{:016x}",
+        exception.mnemonic, exception.name, frame.cs, frame.rip
+    );
+    if exception.error_code != ErrorCode::None {
+        print!(", error code {:#x}", frame.error_code);
+    }
+    println!("\x1b[0m");
+
+    match exception.error_code {
+        ErrorCode::Selector => print_selector_error(frame.error_code),
+        ErrorCode::PageFault => {
+            println!(
+                "  Faulting address {:p}: {:?}",
+                VirtAddr::new_truncate(Cr2::read_raw()),
+                PageFaultErrorCode::from_bits_truncate(frame.error_code)
+            );
+        }
+        ErrorCode::ControlProtection => print_control_protection_error(frame.error_code),
+        ErrorCode::None | ErrorCode::Opaque => {}
+    }
+    print_registers(frame);
+    backtrace::print_from(frame.rip, frame.rbp);
+    hcf()
+}
+
+/// Builds the handlers of the exception `VECTOR`.
+struct HandlerHelpers<const VECTOR: u8>;
+
+impl<const VECTOR: u8> HandlerHelpers<VECTOR> {
+    pub fn halt_interrupt<R: InterruptResult>(ctx: &mut InterruptContext) -> R {
+        report_exception(VECTOR, ctx)
+    }
+
+    /// The error code is also in `ctx`, where it is decoded from.
+    pub fn halt_exception<R: InterruptResult, E: InterruptErrorCode>(
+        ctx: &mut InterruptContext,
+        _: E,
+    ) -> R {
+        report_exception(VECTOR, ctx)
+    }
+}
+
+/// Points every exception vector of `idt` at its handler. Exceptions that can strike while the
+/// stack is unusable get a known good stack from the TSS: #DF, #NMI and #MC use IST 0, #PF IST 1,
+/// and #DB IST 2.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<0>::halt_interrupt));
+    idt.breakpoint
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<3>::halt_interrupt));
+    idt.overflow
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<4>::halt_interrupt));
+    idt.bound_range_exceeded
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<5>::halt_interrupt));
+    idt.invalid_opcode
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<6>::halt_interrupt));
+    idt.device_not_available
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<7>::halt_interrupt));
+    idt.invalid_tss
+        .set_handler_fn(exception_handler(HandlerHelpers::<10>::halt_exception));
+    idt.segment_not_present
+        .set_handler_fn(exception_handler(HandlerHelpers::<11>::halt_exception));
+    idt.stack_segment_fault
+        .set_handler_fn(exception_handler(HandlerHelpers::<12>::halt_exception));
+    idt.general_protection_fault
+        .set_handler_fn(exception_handler(HandlerHelpers::<13>::halt_exception));
+    idt.x87_floating_point
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<16>::halt_interrupt));
+    idt.alignment_check
+        .set_handler_fn(exception_handler(HandlerHelpers::<17>::halt_exception));
+    idt.simd_floating_point
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<19>::halt_interrupt));
+    idt.virtualization
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<20>::halt_interrupt));
+    idt.cp_protection_exception
+        .set_handler_fn(exception_handler(HandlerHelpers::<21>::halt_exception));
+    idt.hv_injection_exception
+        .set_handler_fn(interrupt_handler(HandlerHelpers::<28>::halt_interrupt));
+    idt.vmm_communication_exception
+        .set_handler_fn(exception_handler(HandlerHelpers::<29>::halt_exception));
+    idt.security_exception
+        .set_handler_fn(exception_handler(HandlerHelpers::<30>::halt_exception));
+    unsafe {
+        idt.debug
+            .set_handler_fn(interrupt_handler(HandlerHelpers::<1>::halt_interrupt))
+            .set_stack_index(2);
+        idt.non_maskable_interrupt
+            .set_handler_fn(interrupt_handler(HandlerHelpers::<2>::halt_interrupt))
+            .set_stack_index(0);
+        idt.double_fault
+            .set_handler_fn(exception_handler(HandlerHelpers::<8>::halt_exception))
+            .set_stack_index(0);
+        idt.page_fault
+            .set_handler_fn(exception_handler(HandlerHelpers::<14>::halt_exception))
+            .set_stack_index(1);
+        idt.machine_check
+            .set_handler_fn(interrupt_handler(HandlerHelpers::<18>::halt_interrupt))
+            .set_stack_index(0);
+    }
+}
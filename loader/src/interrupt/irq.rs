@@ -0,0 +1,203 @@
+//! Dispatching device interrupts to the handlers registered with [`los_api::irq::register_irq`].
+//!
+//! Every vector in [`IRQ_VECTORS`] has an entry that looks up the line assigned to it and calls its
+//! handlers in registration order. The line table is only locked with interrupts disabled, so the
+//! entries can take the lock without deadlocking against registration.
+
+use alloc::vec::Vec;
+use core::{
+    ffi::c_void,
+    ops::{Range, RangeInclusive},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use los_api::{
+    arch::x86_64::{InterruptContext, interrupt_handler},
+    irq::{IrqError, IrqFlags, IrqHandler, IrqStatus},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::idt::InterruptDescriptorTable};
+
+use crate::apic::{self, lapic};
+
+/// Vectors assigned to device interrupt lines. Those below are reserved for exceptions and the
+/// local APIC's own interrupts, and those above for the system.
+pub const IRQ_VECTORS: RangeInclusive<u8> = 48..=239;
+
+const VECTOR_COUNT: usize = (*IRQ_VECTORS.end() - *IRQ_VECTORS.start()) as usize + 1;
+
+struct Action {
+    id: u64,
+    handler: IrqHandler,
+    data: *mut c_void,
+}
+
+/// An interrupt line and the handlers registered on it.
+struct Line {
+    gsi: u32,
+    flags: IrqFlags,
+    actions: Vec<Action>,
+}
+
+/// Indexed by vector, starting at the start of [`IRQ_VECTORS`]
+struct Lines([Option<Line>; VECTOR_COUNT]);
+
+// The `data` pointers are only handed back to the handlers they were registered with
+unsafe impl Send for Lines {}
+
+static LINES: Mutex<Lines> = Mutex::new(Lines([const { None }; VECTOR_COUNT]));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn vector_of(idx: usize) -> u8 {
+    IRQ_VECTORS.start() + idx as u8
+}
+
+fn dispatch(vector: u8) {
+    let lines = LINES.lock();
+    if let Some(line) = &lines.0[(vector - IRQ_VECTORS.start()) as usize] {
+        // Every handler is asked, since an edge on a shared line may stand for several devices
+        for action in &line.actions {
+            let _: IrqStatus = unsafe { (action.handler)(action.data) };
+        }
+    }
+    unsafe {
+        lapic().end_of_interrupt();
+    }
+}
+
+fn irq_entry<const VECTOR: u8>(_: &mut InterruptContext) {
+    dispatch(VECTOR)
+}
+
+macro_rules! install_vectors {
+    ($idt:ident; $($high:literal)*) => {
+        $(install_vectors!(@row $idt, $high; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);)*
+    };
+    (@row $idt:ident, $high:literal; $($low:literal)*) => {
+        $(
+            $idt[($high * 16 + $low) as u8]
+                .set_handler_fn(interrupt_handler(irq_entry::<{ $high * 16 + $low }>));
+        )*
+    };
+}
+
+/// Points every vector in [`IRQ_VECTORS`] at its entry.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    install_vectors!(idt; 3 4 5 6 7 8 9 10 11 12 13 14);
+}
+
+/// Registers `handler` on the line `gsi`, assigning the line a vector and routing it if it isn't
+/// claimed yet. Returns the ID to unregister it with and the line's vector.
+pub fn register(
+    gsi: u32,
+    handler: IrqHandler,
+    data: *mut c_void,
+    flags: IrqFlags,
+) -> Result<(u64, u8), IrqError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let action = Action { id, handler, data };
+    interrupts::without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let claimed = lines
+            .0
+            .iter()
+            .position(|line| line.as_ref().is_some_and(|line| line.gsi == gsi));
+        if let Some(idx) = claimed {
+            let line = lines.0[idx].as_mut().unwrap();
+            if !line.flags.contains(IrqFlags::SHARED)
+                || !flags.contains(IrqFlags::SHARED)
+                || line.flags.signalling() != flags.signalling()
+            {
+                return Err(IrqError::Busy);
+            }
+            line.actions.push(action);
+            return Ok((id, vector_of(idx)));
+        }
+
+        let idx = lines
+            .0
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::NoFreeVector)?;
+        lines.0[idx] = Some(Line {
+            gsi,
+            flags,
+            actions: alloc::vec![action],
+        });
+        // Routed last, so the line is in place by the time it first fires
+        if let Err(e) = unsafe { apic::route_gsi(gsi, vector_of(idx), flags) } {
+            lines.0[idx] = None;
+            return Err(e);
+        }
+        Ok((id, vector_of(idx)))
+    })
+}
+
+/// Removes the handlers `pred` selects, masking lines left without any. Returns how many were
+/// removed.
+fn unregister_where(mut pred: impl FnMut(&Action) -> bool) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let mut removed = 0;
+        for slot in &mut lines.0 {
+            let Some(line) = slot else {
+                continue;
+            };
+            let before = line.actions.len();
+            line.actions.retain(|action| !pred(action));
+            removed += before - line.actions.len();
+            if line.actions.is_empty() {
+                unsafe {
+                    apic::mask_gsi(line.gsi);
+                }
+                *slot = None;
+            }
+        }
+        removed
+    })
+}
+
+pub fn unregister(id: u64) {
+    unregister_where(|action| action.id == id);
+}
+
+/// Unregisters every handler whose code lies in `range`, the image of a module being unloaded.
+/// Returns how many there were.
+pub fn unregister_in(range: Range<u64>) -> usize {
+    unregister_where(|action| range.contains(&(action.handler as usize as u64)))
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_register_irq(
+    gsi: u32,
+    handler: IrqHandler,
+    data: *mut c_void,
+    flags: IrqFlags,
+    id: *mut u64,
+    vector: *mut u8,
+) -> u32 {
+    IrqError::into_status(
+        register(gsi, handler, data, flags).map(|(new_id, new_vector)| unsafe {
+            id.write(new_id);
+            vector.write(new_vector);
+        }),
+    )
+}
+los_api::export_symbol!(kernel_register_irq, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_unregister_irq(id: u64) {
+    unregister(id)
+}
+los_api::export_symbol!(kernel_unregister_irq, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_isa_irq(irq: u8, gsi: *mut u32) -> IrqFlags {
+    let (isa_gsi, flags) = apic::isa_irq(irq);
+    unsafe {
+        gsi.write(isa_gsi);
+    }
+    flags
+}
+los_api::export_symbol!(kernel_isa_irq, 1);
@@ -0,0 +1,91 @@
+//! Checking the symbols a module imports against those the loader and other modules export with
+//! [`los_api::export_symbol!`], before the dynamic linker binds them. The dynamic linker itself
+//! knows nothing of the export tables, so this check, run through
+//! [`crate::module::imports_available`] on every module before it is mapped, is what enforces
+//! them.
+
+use alloc::vec::Vec;
+use core::{ffi::CStr, fmt};
+
+use los_api::ksym::{KSYMREQ_SECTION, KSYMTAB_SECTION, SymbolVersion};
+
+use crate::elf::ElfFile;
+
+unsafe extern "C" {
+    // Defined by the linker script around the loader's own `.lilium.ksymtab`
+    static __lilium_ksymtab_start: SymbolVersion;
+    static __lilium_ksymtab_end: SymbolVersion;
+}
+
+/// The symbols the loader itself exports.
+pub fn loader_exports() -> &'static [SymbolVersion] {
+    unsafe {
+        let start = &raw const __lilium_ksymtab_start;
+        let end = &raw const __lilium_ksymtab_end;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// The symbols a module file exports to other modules.
+pub fn exports(elf: &ElfFile) -> Vec<SymbolVersion> {
+    unsafe { elf.section_entries(KSYMTAB_SECTION) }
+}
+
+/// The versions a module file declares it needs of the symbols it imports.
+pub fn requirements(elf: &ElfFile) -> Vec<SymbolVersion> {
+    unsafe { elf.section_entries(KSYMREQ_SECTION) }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ImportError<'a> {
+    /// Nothing exports the symbol
+    Unexported(&'a CStr),
+    /// The symbol is exported, but not at the version the module was built against
+    VersionMismatch {
+        name: &'a CStr,
+        exported: u32,
+        required: u32,
+    },
+}
+
+impl fmt::Display for ImportError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unexported(name) => {
+                write!(f, "imports {}, which is not exported", name.display())
+            }
+            Self::VersionMismatch {
+                name,
+                exported,
+                required,
+            } => write!(
+                f,
+                "needs version {required} of {}, but version {exported} is exported",
+                name.display()
+            ),
+        }
+    }
+}
+
+/// Checks every symbol `elf` imports against `exports`, where the first export of a name wins, as
+/// it does for the dynamic linker. Returns every problem found.
+pub fn check_imports<'a>(elf: &ElfFile<'a>, exports: &[SymbolVersion]) -> Vec<ImportError<'a>> {
+    let requirements = requirements(elf);
+    let mut problems = Vec::new();
+    for name in elf.imports() {
+        let Some(export) = exports.iter().find(|export| export.name() == name) else {
+            problems.push(ImportError::Unexported(name));
+            continue;
+        };
+        if let Some(req) = requirements.iter().find(|req| req.name() == name)
+            && req.version != export.version
+        {
+            problems.push(ImportError::VersionMismatch {
+                name,
+                exported: export.version,
+                required: req.version,
+            });
+        }
+    }
+    problems
+}
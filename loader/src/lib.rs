@@ -16,14 +16,27 @@ extern crate alloc;
 
 #[cfg(target_arch = "x86_64")]
 mod apic;
+mod backtrace;
+mod boot_info;
+#[cfg(target_arch = "x86_64")]
+mod clocksource;
+mod elf;
 mod framebuffer;
+mod handoff;
 mod helpers;
+#[cfg(target_arch = "x86_64")]
+mod hpet;
 mod interrupt;
 mod keyboard;
+mod ksym;
 mod limine_requests;
 mod loader;
 mod memory;
+mod modsig;
+mod module;
 mod prelude;
+#[cfg(target_arch = "x86_64")]
+mod timer;
 mod util;
 
 mod entry;
@@ -40,7 +53,6 @@ use ld_so_impl::{
 use limine::memory_map::EntryType;
 use limine_requests::{BASE_REVISION, FRAMEBUFFER_REQUEST, MEMORY_MAP_REQUEST};
 use los_api::{hcf, println};
-use talc::*;
 use x86_64::{
     VirtAddr,
     registers::segmentation::{CS, SS, Segment},
@@ -53,20 +65,7 @@ use x86_64::{
 
 use crate::{limine_requests::MODULE_REQUEST, loader::RawPageLoader};
 
-const ARENA_SIZE: usize = 0x200000;
-static mut ARENA: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
-
-#[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> = Talc::new(unsafe {
-    // if we're in a hosted environment, the Rust runtime may allocate before
-    // main() is called, so we need to initialize the arena automatically
-    ClaimOnOom::new(Span::from_array(
-        core::ptr::addr_of!(ARENA) as *mut [u8; ARENA_SIZE]
-    ))
-})
-.lock();
-
-static CONSOLE: spin::Once<spin::Mutex<ConsoleOnGraphic<Framebuffer<'static>>>> = spin::Once::new();
+static CONSOLE: spin::Once<spin::Mutex<ConsoleOnGraphic<Framebuffer>>> = spin::Once::new();
 
 fn resolve_error(msg: &CStr, e: Error) -> ! {
     println!("{e:?}: {}", msg.display());
@@ -82,3 +81,4 @@ extern "C" fn print_bytes(data: *const u8, len: usize) {
         .lock()
         .write_bytes(unsafe { core::slice::from_raw_parts(data, len) });
 }
+los_api::export_symbol!(print_bytes, 1);
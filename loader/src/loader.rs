@@ -1,24 +1,210 @@
-use ld_so_impl::loader::LoaderImpl;
+use core::ffi::c_void;
 
-use crate::print_bytes;
+use ld_so_impl::loader::{Error, LoaderImpl};
+use los_api::{
+    cmdline::Value,
+    mem::{AddressRegion, MapError, Protection},
+    println,
+    rand::CsRand,
+};
+use rand_core::RngCore;
+use spin::{Lazy, Mutex};
+use x86_64::VirtAddr;
 
+use crate::{
+    RESOLVER,
+    boot_info::{BootModule, boot_info},
+    elf::{ElfFile, PF_W, PF_X, PT_DYNAMIC, PT_GNU_RELRO, PT_LOAD, Phdr},
+    entry::fill_init_buffer,
+    memory::virt::{self, PAGE_SIZE, region_range},
+    modsig, module, print_bytes,
+};
+
+/// Whether module base addresses are randomized. `--module-kaslr off` places every module at the
+/// lowest free address instead, so addresses are the same from boot to boot when debugging.
+static MODULE_KASLR: Lazy<bool> = Lazy::new(|| match boot_info().options().get("module-kaslr") {
+    Some(Ok(Value::Enum("off"))) => false,
+    Some(Ok(_)) | None => true,
+    Some(Err(e)) => {
+        println!("{e}, randomizing module addresses");
+        true
+    }
+});
+
+/// Picks module base addresses, seeded once from the CPU's entropy sources.
+static KASLR_RNG: Lazy<Mutex<CsRand>> = Lazy::new(|| {
+    let mut rand = None;
+    fill_init_buffer(|seed| rand = Some(CsRand::new(seed)));
+    Mutex::new(rand.unwrap())
+});
+
+/// Random bases tried before falling back to the lowest free address, in case the region is
+/// crowded.
+const KASLR_ATTEMPTS: usize = 16;
+
+/// Reserves `size` bytes of [`AddressRegion::Modules`] for a module image, at a random page in the
+/// region unless [`MODULE_KASLR`] is off.
+fn reserve_image(size: usize) -> Result<VirtAddr, MapError> {
+    let region = region_range(AddressRegion::Modules);
+    if *MODULE_KASLR && size <= region.len() {
+        let slots = ((region.len() - size) / PAGE_SIZE) as u64 + 1;
+        let mut rand = KASLR_RNG.lock();
+        for _ in 0..KASLR_ATTEMPTS {
+            let base = region.start + (rand.next_u64() % slots) as usize * PAGE_SIZE;
+            let base = VirtAddr::new(base as u64);
+            if virt::reserve_at(AddressRegion::Modules, base, size).is_ok() {
+                return Ok(base);
+            }
+        }
+    }
+    virt::reserve(AddressRegion::Modules, size, PAGE_SIZE)
+}
+
+/// Loads modules out of the files Limine loaded for us. The `map_desc` handed around by the
+/// resolver is a pointer to the module's [`BootModule`].
+///
+/// Every module's signature (see [`modsig`]) and imports (see [`module::imports_available`]) are
+/// checked before it is handed out, so a module that is refused can't be pulled in as a
+/// `DT_NEEDED` dependency either.
 pub struct RawPageLoader;
 
+impl RawPageLoader {
+    fn verify(&self, module: &BootModule) -> Result<(), Error> {
+        if modsig::check_module(module) && module::imports_available(module) {
+            Ok(())
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+}
+
+fn module_of<'a>(map_desc: *mut c_void) -> &'a BootModule {
+    unsafe { &*map_desc.cast::<BootModule>() }
+}
+
+/// The page-aligned part of the address space a `PT_LOAD` segment occupies when loaded at `base`.
+pub fn segment_pages(base: usize, phdr: &Phdr) -> (VirtAddr, usize) {
+    let start = (base + phdr.p_vaddr as usize) & !(PAGE_SIZE - 1);
+    let end = (base + (phdr.p_vaddr + phdr.p_memsz) as usize).next_multiple_of(PAGE_SIZE);
+    (VirtAddr::new(start as u64), end - start)
+}
+
+pub fn segment_protection(phdr: &Phdr) -> Protection {
+    let mut prot = Protection::READ;
+    if phdr.p_flags & PF_W != 0 {
+        prot = prot | Protection::WRITE;
+    }
+    if phdr.p_flags & PF_X != 0 {
+        prot = prot | Protection::EXEC;
+    }
+    prot
+}
+
+/// Maps a segment read-write, fills it from the file (zeroing the rest), then applies the
+/// segment's own permissions. Segments that are both writable and executable are refused.
+unsafe fn map_segment(module: &BootModule, base: usize, phdr: &Phdr) -> Result<(), MapError> {
+    if phdr.p_filesz > phdr.p_memsz {
+        return Err(MapError::InvalidArgument);
+    }
+    if phdr.p_flags & (PF_W | PF_X) == PF_W | PF_X {
+        println!(
+            "Refusing to map {} segment at {:#x}: writable and executable",
+            module.name().display(),
+            phdr.p_vaddr
+        );
+        return Err(MapError::InvalidArgument);
+    }
+    let file_data = module
+        .data
+        .get(phdr.p_offset as usize..)
+        .and_then(|data| data.get(..phdr.p_filesz as usize))
+        .ok_or(MapError::InvalidArgument)?;
+
+    let (start, size) = segment_pages(base, phdr);
+    unsafe {
+        virt::map_anonymous(start, size, Protection::WRITE)?;
+        core::ptr::write_bytes(start.as_mut_ptr::<u8>(), 0, size);
+        core::ptr::copy_nonoverlapping(
+            file_data.as_ptr(),
+            (base + phdr.p_vaddr as usize) as *mut u8,
+            file_data.len(),
+        );
+        virt::protect_range(start, size, segment_protection(phdr))
+    }
+}
+
+/// Unmaps and frees every `PT_LOAD` segment of an image loaded at `base`. Segments that were never
+/// mapped are skipped.
+pub unsafe fn unmap_segments(base: usize, phdrs: &[Phdr]) {
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+        let (start, size) = segment_pages(base, phdr);
+        unsafe { virt::unmap_anonymous(start, size) }.ok();
+    }
+}
+
+/// Size of the address space reservation for an image, from its `PT_LOAD` segments.
+pub fn image_size(phdrs: &[Phdr]) -> Option<usize> {
+    phdrs
+        .iter()
+        .filter(|phdr| phdr.p_type == PT_LOAD)
+        .map(|phdr| (phdr.p_vaddr + phdr.p_memsz) as usize)
+        .max()
+        .map(|end| end.next_multiple_of(PAGE_SIZE))
+}
+
+unsafe fn map_segments(module: &BootModule, base: usize, phdrs: &[Phdr]) -> Result<(), Error> {
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+        if unsafe { map_segment(module, base, phdr) }.is_err() {
+            unsafe { unmap_segments(base, phdrs) };
+            return Err(Error::MapError);
+        }
+    }
+    Ok(())
+}
+
+/// Makes the `PT_GNU_RELRO` part of an image loaded at `base` read-only, once relocation is done.
+/// Like other dynamic linkers, only the pages that lie entirely within it are protected.
+///
+/// # Safety
+/// Nothing may write to the range afterwards.
+pub unsafe fn protect_relro(base: usize, phdrs: &[Phdr]) -> Result<(), MapError> {
+    let Some(relro) = phdrs.iter().find(|phdr| phdr.p_type == PT_GNU_RELRO) else {
+        return Ok(());
+    };
+    let start = (base + relro.p_vaddr as usize) & !(PAGE_SIZE - 1);
+    let end = (base + (relro.p_vaddr + relro.p_memsz) as usize) & !(PAGE_SIZE - 1);
+    if end <= start {
+        return Ok(());
+    }
+    unsafe { virt::protect_range(VirtAddr::new(start as u64), end - start, Protection::READ) }
+}
+
 impl LoaderImpl for RawPageLoader {
     unsafe fn alloc_base_addr(
         &self,
-        udata: *mut core::ffi::c_void,
+        _udata: *mut core::ffi::c_void,
         max_pma: ld_so_impl::elf::ElfAddr,
     ) -> Result<*mut core::ffi::c_void, ld_so_impl::loader::Error> {
-        todo!()
+        let size = (max_pma as usize).next_multiple_of(PAGE_SIZE);
+        reserve_image(size)
+            .map(|base| base.as_mut_ptr())
+            .map_err(|_| Error::AllocError)
     }
 
     unsafe fn find(
         &self,
         soname: &core::ffi::CStr,
-        udata: *mut core::ffi::c_void,
+        _udata: *mut core::ffi::c_void,
     ) -> Result<*mut core::ffi::c_void, ld_so_impl::loader::Error> {
-        todo!()
+        boot_info()
+            .modules
+            .iter()
+            .find(|module| module.name() == soname)
+            .ok_or(Error::NotFound)
+            .and_then(|module| {
+                self.verify(module)?;
+                Ok((module as *const BootModule).cast_mut().cast())
+            })
     }
 
     unsafe fn map_phdrs(
@@ -27,7 +213,14 @@ impl LoaderImpl for RawPageLoader {
         map_desc: *mut core::ffi::c_void,
         base_addr: *mut core::ffi::c_void,
     ) -> Result<*mut core::ffi::c_void, ld_so_impl::loader::Error> {
-        todo!()
+        unsafe {
+            map_segments(
+                module_of(map_desc),
+                base_addr as usize,
+                Phdr::from_ld_so(phdrs),
+            )?;
+        }
+        Ok(base_addr)
     }
 
     fn read_offset(
@@ -36,7 +229,13 @@ impl LoaderImpl for RawPageLoader {
         map_desc: *mut core::ffi::c_void,
         sl: &mut [u8],
     ) -> Result<(), ld_so_impl::loader::Error> {
-        todo!()
+        let src = module_of(map_desc)
+            .data
+            .get(off as usize..)
+            .and_then(|data| data.get(..sl.len()))
+            .ok_or(Error::ReadError)?;
+        sl.copy_from_slice(src);
+        Ok(())
     }
 
     fn write_str(&self, st: &str) -> core::fmt::Result {
@@ -46,3 +245,60 @@ impl LoaderImpl for RawPageLoader {
         Ok(())
     }
 }
+
+/// Places a module's segments in [`AddressRegion::Modules`] and links it against everything loaded
+/// so far, loading its `DT_NEEDED` dependencies through [`RawPageLoader`] as needed. Returns the
+/// base address of the image.
+pub fn load_module(module: &'static BootModule) -> Result<VirtAddr, Error> {
+    RawPageLoader.verify(module)?;
+    let elf = ElfFile::parse(module.data).ok_or(Error::ReadError)?;
+    let phdrs = elf.phdrs().ok_or(Error::ReadError)?;
+    let size = image_size(&phdrs).ok_or(Error::ReadError)?;
+    let dynamic = phdrs
+        .iter()
+        .find(|phdr| phdr.p_type == PT_DYNAMIC)
+        .ok_or(Error::ReadError)?;
+
+    let map_desc = (module as *const BootModule).cast_mut().cast();
+    unsafe {
+        let base = RawPageLoader.alloc_base_addr(map_desc, size as _)?;
+        if let Err(e) = map_segments(module, base as usize, &phdrs) {
+            virt::release(AddressRegion::Modules, VirtAddr::from_ptr(base), size)
+                .expect("module image was just reserved");
+            return Err(e);
+        }
+        (*RESOLVER.get()).resolve_object(
+            base as _,
+            (base as usize + dynamic.p_vaddr as usize) as _,
+            Some(module.name()),
+            map_desc,
+            !0,
+            None,
+        );
+        if protect_relro(base as usize, &phdrs).is_err() {
+            unmap_segments(base as usize, &phdrs);
+            virt::release(AddressRegion::Modules, VirtAddr::from_ptr(base), size)
+                .expect("module image was just reserved");
+            return Err(Error::MapError);
+        }
+        Ok(VirtAddr::from_ptr(base))
+    }
+}
+
+/// Unmaps an image loaded by [`load_module`] and returns its address space.
+///
+/// # Safety
+/// Nothing may use the image afterwards, including its code, data and any symbol the dynamic
+/// linker resolved to it.
+pub unsafe fn unload_module(module: &BootModule, base: VirtAddr) {
+    let Some(phdrs) = ElfFile::parse(module.data).and_then(|elf| elf.phdrs()) else {
+        return;
+    };
+    unsafe {
+        unmap_segments(base.as_u64() as usize, &phdrs);
+        if let Some(size) = image_size(&phdrs) {
+            virt::release(AddressRegion::Modules, base, size)
+                .expect("module image wasn't reserved");
+        }
+    }
+}
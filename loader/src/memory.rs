@@ -1,124 +1,173 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use acpi::{AcpiHandler, PhysicalMapping};
-use limine::memory_map::{self, EntryType};
+use limine::memory_map::EntryType;
+use los_api::mem::CacheMode;
 use spin::{Lazy, Mutex};
 use x86_64::{
     PhysAddr, VirtAddr,
     registers::control::Cr3,
     structures::paging::{
         FrameAllocator, MappedPageTable, Mapper, Page, PageTable, PageTableFlags, PhysFrame,
-        Size4KiB, mapper::PageTableFrameMapping,
+        Size4KiB, Translate, mapper::PageTableFrameMapping,
     },
 };
 
-use crate::{MEMORY_MAP_REQUEST, limine_requests::HHDM_REQUEST, println};
+use crate::{
+    boot_info::{boot_info, hhdm_offset},
+    println,
+};
+
+pub mod heap;
+pub mod phys;
+pub mod virt;
+pub mod wx;
+
+use phys::GlobalFrameAllocator;
 
 struct FrameMappping;
 
 unsafe impl PageTableFrameMapping for FrameMappping {
     fn frame_to_pointer(&self, frame: PhysFrame) -> *mut PageTable {
         let phys = frame.start_address().as_u64();
-        let hhdm_offset = HHDM_REQUEST.get_response().unwrap().offset();
-        let virt = phys + hhdm_offset;
+        let virt = phys + hhdm_offset();
         virt as *mut PageTable
     }
 }
 
-static PAGE_TABLE: Lazy<PhysAddr> = Lazy::new(|| Cr3::read().0.start_address());
+/// Builds a mapper over whatever level 4 table is currently loaded in CR3.
+unsafe fn active_mapper() -> MappedPageTable<'static, FrameMappping> {
+    let level_4_table = FrameMappping.frame_to_pointer(Cr3::read().0);
+    unsafe { MappedPageTable::new(&mut *level_4_table, FrameMappping) }
+}
+
 static PAGE_TABLE_MAPPING: Lazy<Mutex<MappedPageTable<FrameMappping>>> = Lazy::new(|| unsafe {
-    println!(
-        "HHDM offset: {:#X}",
-        HHDM_REQUEST.get_response().unwrap().offset()
-    );
-    Mutex::new(MappedPageTable::new(
-        &mut *(VirtAddr::new(PAGE_TABLE.as_u64() + HHDM_REQUEST.get_response().unwrap().offset())
-            .as_mut_ptr()),
-        FrameMappping,
-    ))
+    println!("HHDM offset: {:#X}", hhdm_offset());
+    Mutex::new(active_mapper())
 });
 
-struct BootInfoFrameAllocator {
-    entries: &'static [&'static memory_map::Entry],
-    next: usize,
+static BOOT_MEMORY_RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Whether [`reclaim_boot_memory`] has run, i.e. whether Limine responses and the firmware ACPI
+/// tables may still be dereferenced.
+pub fn boot_memory_reclaimed() -> bool {
+    BOOT_MEMORY_RECLAIMED.load(Ordering::Acquire)
 }
 
-impl BootInfoFrameAllocator {
-    pub fn new(memory_map: &'static [&'static memory_map::Entry]) -> Self {
-        Self {
-            entries: memory_map,
-            next: 0,
+/// Recursively copies the page table at `table` (of the given paging `level`) into frames owned by
+/// the frame allocator. Leaf entries, including huge pages, are copied verbatim.
+unsafe fn copy_page_table(table: &PageTable, level: u8) -> PhysFrame {
+    let frame = GlobalFrameAllocator
+        .allocate_frame()
+        .expect("out of memory copying page tables");
+    let copy = unsafe { &mut *FrameMappping.frame_to_pointer(frame) };
+    copy.zero();
+
+    for (src, dst) in table.iter().zip(copy.iter_mut()) {
+        let flags = src.flags();
+        if level > 1
+            && flags.contains(PageTableFlags::PRESENT)
+            && !flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            let child = unsafe { &*FrameMappping.frame_to_pointer(src.frame().unwrap()) };
+            dst.set_frame(unsafe { copy_page_table(child, level - 1) }, flags);
+        } else {
+            *dst = src.clone();
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
-        self.entries
-            .iter()
-            .filter(|e| e.entry_type == EntryType::USABLE)
-            .flat_map(|entry| {
-                let start = entry.base;
-                let end = entry.base + entry.length;
-                (start..end)
-                    .step_by(4096)
-                    .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-            })
-    }
+    frame
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        // TODO: This is a stupid solution. It works, but it is in fact stupid. Let's not do this.
-        let frame = self.usable_frames().nth(self.next)?;
-        self.next += 1;
-        Some(frame)
+/// Makes sure every page of `[base, base + length)` is reachable through the HHDM. Limine only
+/// maps some memory map entry types there.
+unsafe fn ensure_hhdm_mapped(base: u64, length: u64) {
+    let mut mapper = PAGE_TABLE_MAPPING.lock();
+    let start = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(base));
+    let end = PhysFrame::containing_address(PhysAddr::new(base + length - 1));
+    for frame in PhysFrame::range_inclusive(start, end) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+            frame.start_address().as_u64() + hhdm_offset(),
+        ));
+        if mapper.translate_addr(page.start_address()).is_none() {
+            unsafe {
+                mapper
+                    .map_to(
+                        page,
+                        frame,
+                        PageTableFlags::PRESENT
+                            | PageTableFlags::WRITABLE
+                            | PageTableFlags::NO_EXECUTE,
+                        &mut GlobalFrameAllocator,
+                    )
+                    .unwrap()
+                    .flush();
+            }
+        }
     }
 }
 
-static FRAME_ALLOCATOR: Lazy<Mutex<BootInfoFrameAllocator>> = Lazy::new(|| {
-    Mutex::new(BootInfoFrameAllocator::new(
-        MEMORY_MAP_REQUEST.get_response().unwrap().entries(),
-    ))
-});
-
-#[derive(Clone, Copy)]
-pub struct BasicAcpiHandler;
-
-pub unsafe fn map_physical_region(physical_address: usize, size: usize) -> usize {
-    let offset = HHDM_REQUEST.get_response().unwrap().offset();
-    let virt = physical_address + offset as usize;
-
-    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(virt as u64));
-    let frame = PhysFrame::containing_address(PhysAddr::new(physical_address as u64));
+/// Hands `BOOTLOADER_RECLAIMABLE` and `ACPI_RECLAIMABLE` memory to the frame allocator.
+///
+/// The page tables Limine built live in bootloader-reclaimable memory, so they are first copied
+/// into loader-owned frames and CR3 is switched over to the copy.
+///
+/// # Safety
+/// This marks the end of early boot: once it returns, every pointer obtained from a Limine response
+/// (anything reached through `limine_requests`) and every pointer into the firmware ACPI tables is
+/// dangling. Everything still needed must have been copied out beforehand, either into
+/// [`crate::boot_info::BootInfo`] or into owned structures such as the parsed MADT.
+pub unsafe fn reclaim_boot_memory() {
+    assert!(
+        !BOOT_MEMORY_RECLAIMED.swap(true, Ordering::AcqRel),
+        "boot memory reclaimed twice"
+    );
 
-    let mut mapper = PAGE_TABLE_MAPPING.lock();
-    if mapper.translate_page(page).is_err() {
-        println!("allocating a page at {physical_address:#X} with a size of {size:#X}");
+    {
+        let mut mapper = PAGE_TABLE_MAPPING.lock();
+        let (_, cr3_flags) = Cr3::read();
         unsafe {
-            mapper
-                .map_to(
-                    page,
-                    frame,
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                    &mut *FRAME_ALLOCATOR.lock(),
-                )
-                .unwrap()
-                .flush();
+            let level_4_table = copy_page_table(mapper.level_4_table(), 4);
+            Cr3::write(level_4_table, cr3_flags);
+            *mapper = active_mapper();
         }
     }
 
-    virt
+    for region in &boot_info().memory_map {
+        if region.kind != EntryType::BOOTLOADER_RECLAIMABLE
+            && region.kind != EntryType::ACPI_RECLAIMABLE
+        {
+            continue;
+        }
+        unsafe {
+            ensure_hhdm_mapped(region.base, region.length);
+            phys::reclaim_range(region.base, region.length);
+        }
+    }
 }
 
+#[derive(Clone, Copy)]
+pub struct BasicAcpiHandler;
+
 impl AcpiHandler for BasicAcpiHandler {
     unsafe fn map_physical_region<T>(
         &self,
         physical_address: usize,
         size: usize,
     ) -> PhysicalMapping<Self, T> {
-        let virt = unsafe { map_physical_region(physical_address, size) };
+        let virt = unsafe {
+            virt::map_mmio(
+                PhysAddr::new(physical_address as u64),
+                size,
+                CacheMode::WriteBack,
+            )
+        }
+        .expect("failed to map ACPI table");
 
         unsafe {
             PhysicalMapping::new(
                 physical_address,
-                core::ptr::NonNull::new(virt as *mut T).unwrap(),
+                core::ptr::NonNull::new(virt.as_mut_ptr()).unwrap(),
                 size,
                 size,
                 *self,
@@ -126,5 +175,13 @@ impl AcpiHandler for BasicAcpiHandler {
         }
     }
 
-    fn unmap_physical_region<T>(_: &PhysicalMapping<Self, T>) {}
+    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
+        unsafe {
+            virt::unmap_mmio(
+                VirtAddr::from_ptr(region.virtual_start().as_ptr()),
+                region.mapped_length(),
+            )
+        }
+        .expect("failed to unmap ACPI table");
+    }
 }
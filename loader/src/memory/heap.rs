@@ -0,0 +1,168 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use los_api::mem::{AddressRegion, HeapStats, Protection};
+use talc::{OomHandler, Span, Talc, Talck};
+use x86_64::VirtAddr;
+
+use super::virt::{self, region_range};
+
+/// The heap grows by at least this much at a time, in whole huge pages where memory allows
+const MIN_GROW: usize = virt::HUGE_PAGE_SIZE;
+
+/// Statically allocated memory that serves allocations until the frame allocator is available
+const ARENA_SIZE: usize = 0x40000;
+static mut ARENA: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
+
+/// Talc OOM handler that first claims [`ARENA`], then grows a span from the start of
+/// [`AddressRegion::Heap`] by mapping fresh frames behind it.
+pub struct GrowableHeap {
+    arena_claimed: bool,
+    heap: Span,
+    /// Total bytes claimed from the arena and the growable span
+    size: usize,
+}
+
+impl GrowableHeap {
+    const fn new() -> Self {
+        Self {
+            arena_claimed: false,
+            heap: Span::empty(),
+            size: 0,
+        }
+    }
+}
+
+/// Maps fresh frames at `[start, start + size)`.
+fn map_heap_pages(start: usize, size: usize) -> bool {
+    let start = VirtAddr::new(start as u64);
+    unsafe { virt::map_anonymous(start, size, Protection::WRITE) }.is_ok()
+}
+
+impl OomHandler for GrowableHeap {
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        if !talc.oom_handler.arena_claimed {
+            talc.oom_handler.arena_claimed = true;
+            let arena = unsafe {
+                talc.claim(Span::from_array(
+                    core::ptr::addr_of!(ARENA) as *mut [u8; ARENA_SIZE]
+                ))?
+            };
+            talc.oom_handler.size += arena.size();
+            return Ok(());
+        }
+
+        let heap_range = region_range(AddressRegion::Heap);
+        let old_heap = talc.oom_handler.heap;
+        let (base, acme) = old_heap
+            .get_base_acme()
+            .unwrap_or((heap_range.start as *mut u8, heap_range.start as *mut u8));
+        let current = acme as usize - base as usize;
+
+        // Grow by at least a quarter of the current size so large heaps don't take an OOM round
+        // trip for every few allocations, falling back to just what this allocation needs if memory
+        // is tight. The extra `align` covers talc's own bookkeeping and padding.
+        let needed = (layout.size() + layout.align() * 2).next_multiple_of(4096);
+        let wanted = needed.max(current / 4).next_multiple_of(MIN_GROW);
+        let grow = [wanted, needed]
+            .into_iter()
+            .filter(|&size| acme as usize + size <= heap_range.end)
+            .find(|&size| map_heap_pages(acme as usize, size))
+            .ok_or(())?;
+
+        let requested = Span::new(base, acme.wrapping_add(grow));
+        let new_heap = if old_heap.is_empty() {
+            unsafe { talc.claim(requested)? }
+        } else {
+            unsafe { talc.extend(old_heap, requested) }
+        };
+        talc.oom_handler.size += new_heap.size() - old_heap.size();
+        talc.oom_handler.heap = new_heap;
+        Ok(())
+    }
+}
+
+/// The global allocator: talc with a growable heap, plus usage accounting for [`HeapStats`].
+pub struct KernelHeap {
+    talc: Talck<spin::Mutex<()>, GrowableHeap>,
+    used: AtomicUsize,
+    high_water_mark: AtomicUsize,
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        Self {
+            talc: Talc::new(GrowableHeap::new()).lock(),
+            used: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+        }
+    }
+
+    fn grew(&self, bytes: usize) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.high_water_mark.fetch_max(used, Ordering::Relaxed);
+    }
+
+    fn shrank(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// The part of [`AddressRegion::Heap`] the heap has grown into so far
+    pub fn mapped_range(&self) -> Range<usize> {
+        match self.talc.lock().oom_handler.heap.get_base_acme() {
+            Some((base, acme)) => base as usize..acme as usize,
+            None => 0..0,
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let size = self.talc.lock().oom_handler.size;
+        let used = self.used.load(Ordering::Relaxed);
+        HeapStats {
+            used,
+            free: size.saturating_sub(used),
+            high_water_mark: self.high_water_mark.load(Ordering::Relaxed),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.talc.alloc(layout) };
+        if !ptr.is_null() {
+            self.grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.talc.dealloc(ptr, layout) };
+        self.shrank(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { self.talc.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                self.grew(new_size - layout.size());
+            } else {
+                self.shrank(layout.size() - new_size);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+pub static ALLOCATOR: KernelHeap = KernelHeap::new();
+
+#[unsafe(no_mangle)]
+extern "C" fn get_heap_stats(stats: *mut HeapStats) {
+    unsafe {
+        stats.write(ALLOCATOR.stats());
+    }
+}
+los_api::export_symbol!(get_heap_stats, 1);
@@ -0,0 +1,346 @@
+use limine::memory_map::EntryType;
+use spin::{Lazy, Mutex};
+use x86_64::{
+    PhysAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB, frame::PhysFrameRange,
+    },
+};
+
+use crate::boot_info::{MemoryRegion, boot_info};
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Largest block handed out by the buddy allocator, as a power of two number of frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+
+const NIL: u64 = u64::MAX;
+
+/// Physical address ranges an allocation may be satisfied from.
+///
+/// The zones nest: a [`Zone::Dma32`] request may be served from [`Zone::Low`], and a [`Zone::Any`]
+/// request from either of the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Zone {
+    /// Below 1 MiB, for real-mode trampolines and ISA DMA
+    Low,
+    /// Below 4 GiB, for devices that can only address 32 bits
+    Dma32,
+    /// Anywhere in physical memory
+    Any,
+}
+
+const ZONE_COUNT: usize = 3;
+
+/// Exclusive upper bound (in frames) of each zone
+const ZONE_LIMITS: [u64; ZONE_COUNT] = [0x100, 0x10_0000, u64::MAX];
+
+fn pfn_of(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / FRAME_SIZE
+}
+
+fn frame_of(pfn: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(pfn * FRAME_SIZE))
+}
+
+/// Regions the allocator hands out from the start.
+fn is_usable(region: &MemoryRegion) -> bool {
+    region.kind == EntryType::USABLE
+}
+
+/// Regions the allocator keeps metadata for, including those that only become free once
+/// [`crate::memory::reclaim_boot_memory`] has run.
+fn is_managed(region: &MemoryRegion) -> bool {
+    is_usable(region)
+        || region.kind == EntryType::BOOTLOADER_RECLAIMABLE
+        || region.kind == EntryType::ACPI_RECLAIMABLE
+}
+
+/// Stored in the first bytes of every free block, linking it into the free list of its order.
+#[repr(C)]
+struct FreeNode {
+    next: u64,
+    prev: u64,
+}
+
+struct ZoneArea {
+    start: u64,
+    end: u64,
+    free_lists: [u64; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+}
+
+/// A binary buddy allocator over the physical memory described by the Limine memory map.
+///
+/// Free blocks are kept in intrusive doubly linked lists that live inside the free frames
+/// themselves (accessed through the HHDM), so the only out-of-line metadata is one byte per frame
+/// recording the order of the free block starting there.
+pub struct PhysicalMemoryManager {
+    hhdm_offset: u64,
+    /// `order + 1` for the first frame of every free block, `0` for everything else
+    free_order: &'static mut [u8],
+    zones: [ZoneArea; ZONE_COUNT],
+    total_frames: usize,
+}
+
+impl PhysicalMemoryManager {
+    /// # Safety
+    /// Every region in `entries` accepted by [`is_usable`] must be unused and mapped in the HHDM at
+    /// `hhdm_offset`.
+    unsafe fn new(entries: &[MemoryRegion], hhdm_offset: u64) -> Self {
+        let end_pfn = entries
+            .iter()
+            .filter(|e| is_managed(e))
+            .map(|e| (e.base + e.length) / FRAME_SIZE)
+            .max()
+            .unwrap_or(0);
+
+        // Carve the `free_order` table out of the first region that can hold it, preferring to keep
+        // memory below 1 MiB for the devices and trampolines that need it.
+        let meta_frames = end_pfn.div_ceil(FRAME_SIZE);
+        let meta_pfn = entries
+            .iter()
+            .filter(|e| is_usable(e))
+            .map(|e| {
+                (
+                    e.base.div_ceil(FRAME_SIZE),
+                    (e.base + e.length) / FRAME_SIZE,
+                )
+            })
+            .filter(|&(start, end)| end.saturating_sub(start) >= meta_frames)
+            .min_by_key(|&(start, _)| (start < ZONE_LIMITS[Zone::Low as usize], start))
+            .map(|(start, _)| start)
+            .expect("no usable region is large enough for the frame allocator metadata");
+
+        let free_order = unsafe {
+            core::slice::from_raw_parts_mut(
+                (meta_pfn * FRAME_SIZE + hhdm_offset) as *mut u8,
+                end_pfn as usize,
+            )
+        };
+        free_order.fill(0);
+
+        let mut this = Self {
+            hhdm_offset,
+            free_order,
+            zones: core::array::from_fn(|i| ZoneArea {
+                start: if i == 0 { 0 } else { ZONE_LIMITS[i - 1] }.min(end_pfn),
+                end: ZONE_LIMITS[i].min(end_pfn),
+                free_lists: [NIL; MAX_ORDER + 1],
+                free_frames: 0,
+            }),
+            total_frames: 0,
+        };
+
+        for entry in entries.iter().filter(|e| is_usable(e)) {
+            let start = entry.base.div_ceil(FRAME_SIZE);
+            let end = (entry.base + entry.length) / FRAME_SIZE;
+            let meta_end = meta_pfn + meta_frames;
+            // The metadata always starts a region, so only the part after it is free
+            let start = if start == meta_pfn { meta_end } else { start };
+            if start < end {
+                this.total_frames += (end - start) as usize;
+                this.free_range(start, end);
+            }
+        }
+
+        this
+    }
+
+    fn node(&self, pfn: u64) -> *mut FreeNode {
+        (pfn * FRAME_SIZE + self.hhdm_offset) as *mut FreeNode
+    }
+
+    fn link(&mut self, zone: usize, pfn: u64, order: usize) {
+        let head = self.zones[zone].free_lists[order];
+        unsafe {
+            self.node(pfn).write(FreeNode {
+                next: head,
+                prev: NIL,
+            });
+            if head != NIL {
+                (*self.node(head)).prev = pfn;
+            }
+        }
+        self.zones[zone].free_lists[order] = pfn;
+        self.free_order[pfn as usize] = order as u8 + 1;
+    }
+
+    fn unlink(&mut self, zone: usize, pfn: u64, order: usize) {
+        let FreeNode { next, prev } = unsafe { self.node(pfn).read() };
+        unsafe {
+            if prev == NIL {
+                self.zones[zone].free_lists[order] = next;
+            } else {
+                (*self.node(prev)).next = next;
+            }
+            if next != NIL {
+                (*self.node(next)).prev = prev;
+            }
+        }
+        self.free_order[pfn as usize] = 0;
+    }
+
+    fn free_block(&mut self, zone: usize, mut pfn: u64, mut order: usize) {
+        assert_eq!(
+            self.free_order[pfn as usize],
+            0,
+            "double free of physical frame {:#X}",
+            pfn * FRAME_SIZE
+        );
+        self.zones[zone].free_frames += 1 << order;
+
+        let (start, end) = (self.zones[zone].start, self.zones[zone].end);
+        while order < MAX_ORDER {
+            let buddy = pfn ^ (1 << order);
+            if buddy < start
+                || buddy + (1 << order) > end
+                || self.free_order[buddy as usize] != order as u8 + 1
+            {
+                break;
+            }
+            self.unlink(zone, buddy, order);
+            pfn = pfn.min(buddy);
+            order += 1;
+        }
+        self.link(zone, pfn, order);
+    }
+
+    fn alloc_block(&mut self, zone: usize, order: usize) -> Option<u64> {
+        let found = (order..=MAX_ORDER).find(|&o| self.zones[zone].free_lists[o] != NIL)?;
+        let pfn = self.zones[zone].free_lists[found];
+        self.unlink(zone, pfn, found);
+        for o in (order..found).rev() {
+            self.link(zone, pfn + (1 << o), o);
+        }
+        self.zones[zone].free_frames -= 1 << order;
+        Some(pfn)
+    }
+
+    /// Frees every frame in `[start, end)`, splitting the range into the largest naturally aligned
+    /// blocks that fit and never letting a block cross a zone boundary.
+    fn free_range(&mut self, start: u64, end: u64) {
+        for zone in 0..ZONE_COUNT {
+            let mut pfn = start.max(self.zones[zone].start);
+            let end = end.min(self.zones[zone].end);
+            while pfn < end {
+                let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER);
+                while pfn + (1 << order) > end {
+                    order -= 1;
+                }
+                self.free_block(zone, pfn, order);
+                pfn += 1 << order;
+            }
+        }
+    }
+
+    pub fn allocate_contiguous(&mut self, count: usize, zone: Zone) -> Option<PhysFrameRange> {
+        assert_ne!(count, 0, "cannot allocate zero frames");
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // Prefer the highest zone the caller accepts, so low memory stays free for those that need
+        // it
+        let pfn = (0..=zone as usize)
+            .rev()
+            .find_map(|z| self.alloc_block(z, order))?;
+        let end = pfn + count as u64;
+        self.free_range(end, pfn + (1 << order));
+
+        Some(PhysFrame::range(frame_of(pfn), frame_of(end)))
+    }
+
+    /// # Safety
+    /// `range` must have been returned by [`Self::allocate_contiguous`] (or be a subrange of such
+    /// an allocation) and must no longer be in use.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        self.free_range(pfn_of(range.start), pfn_of(range.end));
+    }
+
+    /// # Safety
+    /// `[base, base + length)` must be a reclaimable region of the memory map that is no longer in
+    /// use and is mapped in the HHDM.
+    pub unsafe fn reclaim_range(&mut self, base: u64, length: u64) {
+        let start = base.div_ceil(FRAME_SIZE);
+        let end = (base + length) / FRAME_SIZE;
+        if start < end {
+            self.total_frames += (end - start) as usize;
+            self.free_range(start, end);
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            free_frames: self.zones.iter().map(|z| z.free_frames).sum(),
+        }
+    }
+}
+
+static PMM: Lazy<Mutex<PhysicalMemoryManager>> = Lazy::new(|| unsafe {
+    let boot_info = boot_info();
+    Mutex::new(PhysicalMemoryManager::new(
+        &boot_info.memory_map,
+        boot_info.hhdm_offset,
+    ))
+});
+
+pub fn allocate_frame_in(zone: Zone) -> Option<PhysFrame> {
+    Some(PMM.lock().allocate_contiguous(1, zone)?.start)
+}
+
+pub fn allocate_contiguous(count: usize, zone: Zone) -> Option<PhysFrameRange> {
+    PMM.lock().allocate_contiguous(count, zone)
+}
+
+/// # Safety
+/// `frame` must have been allocated from the frame allocator and must no longer be in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    unsafe {
+        deallocate_contiguous(PhysFrame::range(frame, frame + 1));
+    }
+}
+
+/// # Safety
+/// See [`PhysicalMemoryManager::deallocate_contiguous`].
+pub unsafe fn deallocate_contiguous(range: PhysFrameRange) {
+    unsafe {
+        PMM.lock().deallocate_contiguous(range);
+    }
+}
+
+/// # Safety
+/// See [`PhysicalMemoryManager::reclaim_range`].
+pub unsafe fn reclaim_range(base: u64, length: u64) {
+    unsafe {
+        PMM.lock().reclaim_range(base, length);
+    }
+}
+
+pub fn stats() -> FrameStats {
+    PMM.lock().stats()
+}
+
+/// Adapter that lets the `x86_64` page table mappers allocate from the global frame allocator.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame_in(Zone::Any)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { deallocate_frame(frame) }
+    }
+}
@@ -0,0 +1,748 @@
+use alloc::vec::Vec;
+use core::{ffi::c_void, ops::Range};
+
+use los_api::mem::{AddressRegion, CacheMode, MapError, Protection};
+use spin::{Lazy, Mutex};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        FrameAllocator, MappedPageTable, Mapper, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+        frame::PhysFrameRange,
+        mapper::{MapToError, MappedFrame, PageTableFrameMapping, TranslateResult},
+    },
+};
+
+use super::{
+    FrameMappping, PAGE_TABLE_MAPPING,
+    phys::{self, GlobalFrameAllocator, Zone},
+};
+
+pub const PAGE_SIZE: usize = 4096;
+pub const HUGE_PAGE_SIZE: usize = Size2MiB::SIZE as usize;
+pub const GIANT_PAGE_SIZE: usize = Size1GiB::SIZE as usize;
+
+type ActiveMapper = MappedPageTable<'static, FrameMappping>;
+
+/// Whether the CPU supports 1 GiB pages (`CPUID.80000001H:EDX.Page1GB`)
+static HAS_GIANT_PAGES: Lazy<bool> = Lazy::new(|| {
+    use core::arch::x86_64::__cpuid;
+    __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+});
+
+/// Layout of the parts of the kernel half the loader manages. The HHDM and the loader image itself
+/// are placed by Limine, below and above these respectively.
+pub fn region_range(region: AddressRegion) -> Range<usize> {
+    match region {
+        AddressRegion::Heap => 0xFFFF_C000_0000_0000..0xFFFF_C010_0000_0000,
+        AddressRegion::Modules => 0xFFFF_D000_0000_0000..0xFFFF_D010_0000_0000,
+        AddressRegion::Mmio => 0xFFFF_E000_0000_0000..0xFFFF_E010_0000_0000,
+        AddressRegion::Stacks => 0xFFFF_F000_0000_0000..0xFFFF_F010_0000_0000,
+        _ => 0..0,
+    }
+}
+
+const REGIONS: [AddressRegion; 4] = [
+    AddressRegion::Heap,
+    AddressRegion::Modules,
+    AddressRegion::Mmio,
+    AddressRegion::Stacks,
+];
+
+fn region_index(region: AddressRegion) -> Option<usize> {
+    REGIONS.iter().position(|&r| r == region)
+}
+
+/// Free address ranges of one region, sorted and never adjacent.
+struct FreeRanges(Vec<Range<usize>>);
+
+impl FreeRanges {
+    fn take(&mut self, idx: usize, range: Range<usize>) {
+        let free = self.0[idx].clone();
+        let before = free.start..range.start;
+        let after = range.end..free.end;
+        match (before.is_empty(), after.is_empty()) {
+            (true, true) => {
+                self.0.remove(idx);
+            }
+            (true, false) => self.0[idx] = after,
+            (false, true) => self.0[idx] = before,
+            (false, false) => {
+                self.0[idx] = before;
+                self.0.insert(idx + 1, after);
+            }
+        }
+    }
+
+    fn reserve(&mut self, size: usize, align: usize) -> Option<usize> {
+        let (idx, start) = self.0.iter().enumerate().find_map(|(idx, free)| {
+            let start = free.start.next_multiple_of(align);
+            (start.checked_add(size)? <= free.end).then_some((idx, start))
+        })?;
+        self.take(idx, start..start + size);
+        Some(start)
+    }
+
+    fn reserve_at(&mut self, range: Range<usize>) -> bool {
+        let Some(idx) = self
+            .0
+            .iter()
+            .position(|free| free.start <= range.start && range.end <= free.end)
+        else {
+            return false;
+        };
+        self.take(idx, range);
+        true
+    }
+
+    /// Returns the range to the free list, unless part of it is already free.
+    fn release(&mut self, range: Range<usize>) -> bool {
+        let idx = self.0.partition_point(|free| free.start < range.start);
+        if (idx > 0 && self.0[idx - 1].end > range.start)
+            || (idx < self.0.len() && self.0[idx].start < range.end)
+        {
+            return false;
+        }
+        let merges_prev = idx > 0 && self.0[idx - 1].end == range.start;
+        let merges_next = idx < self.0.len() && self.0[idx].start == range.end;
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.0[idx - 1].end = self.0[idx].end;
+                self.0.remove(idx);
+            }
+            (true, false) => self.0[idx - 1].end = range.end,
+            (false, true) => self.0[idx].start = range.start,
+            (false, false) => self.0.insert(idx, range),
+        }
+        true
+    }
+}
+
+/// The heap grows in place (see [`super::heap`]), so its free list starts out empty.
+static ADDRESS_SPACE: Lazy<Mutex<[FreeRanges; 4]>> = Lazy::new(|| {
+    Mutex::new(REGIONS.map(|region| match region {
+        AddressRegion::Heap => FreeRanges(Vec::new()),
+        region => FreeRanges(alloc::vec![region_range(region)]),
+    }))
+});
+
+fn check_size(size: usize) -> Result<(), MapError> {
+    if size == 0 || size % PAGE_SIZE != 0 {
+        Err(MapError::InvalidArgument)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn reserve(region: AddressRegion, size: usize, align: usize) -> Result<VirtAddr, MapError> {
+    check_size(size)?;
+    if !align.is_power_of_two() {
+        return Err(MapError::InvalidArgument);
+    }
+    let idx = region_index(region).ok_or(MapError::InvalidArgument)?;
+    ADDRESS_SPACE.lock()[idx]
+        .reserve(size, align.max(PAGE_SIZE))
+        .map(|addr| VirtAddr::new(addr as u64))
+        .ok_or(MapError::OutOfAddressSpace)
+}
+
+/// Reserves exactly `[addr, addr + size)`, failing if any of it is already reserved.
+pub fn reserve_at(region: AddressRegion, addr: VirtAddr, size: usize) -> Result<(), MapError> {
+    check_size(size)?;
+    let idx = region_index(region).ok_or(MapError::InvalidArgument)?;
+    let start = addr.as_u64() as usize;
+    if ADDRESS_SPACE.lock()[idx].reserve_at(start..start + size) {
+        Ok(())
+    } else {
+        Err(MapError::AlreadyMapped)
+    }
+}
+
+/// Returns `[addr, addr + size)` to `region`. Fails with [`MapError::InvalidArgument`] if the range
+/// isn't page aligned, lies outside the region or is already partly free, which is all that can be
+/// checked of a release. The heap can't be released into, as its free list must stay empty.
+///
+/// # Safety
+/// The range must have been reserved from `region` and nothing may be mapped in it.
+pub unsafe fn release(region: AddressRegion, addr: VirtAddr, size: usize) -> Result<(), MapError> {
+    check_size(size)?;
+    if region == AddressRegion::Heap || !addr.is_aligned(PAGE_SIZE as u64) {
+        return Err(MapError::InvalidArgument);
+    }
+    let idx = region_index(region).ok_or(MapError::InvalidArgument)?;
+    let start = addr.as_u64() as usize;
+    let range = start..start.checked_add(size).ok_or(MapError::InvalidArgument)?;
+    let bounds = region_range(region);
+    if range.start < bounds.start || range.end > bounds.end {
+        return Err(MapError::InvalidArgument);
+    }
+    if ADDRESS_SPACE.lock()[idx].release(range) {
+        Ok(())
+    } else {
+        Err(MapError::InvalidArgument)
+    }
+}
+
+/// PAT bit of a 4 KiB entry. Higher levels use this bit for [`PageTableFlags::HUGE_PAGE`] and move
+/// the PAT bit to bit 12, which `x86_64` treats as part of the frame address. Mappings that need it
+/// are therefore always made of 4 KiB pages.
+const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// PAT bit of a 2 MiB or 1 GiB entry, as it appears in the entry's address
+const PAT_HUGE: u64 = 1 << 12;
+
+const CACHE_FLAGS: PageTableFlags = PageTableFlags::WRITE_THROUGH
+    .union(PageTableFlags::NO_CACHE)
+    .union(PAT_4KIB);
+
+/// Intermediate tables never restrict access; leaf entries carry the permissions.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+const IA32_PAT: u32 = 0x277;
+
+/// PAT entries, in order: WB, WC, UC-, UC, WP, WC, UC-, WT.
+///
+/// Write-combining sits in entry 1 so that framebuffers can be mapped with huge pages without the
+/// PAT bit, leaving write-through as the only cache mode that needs it. Entries 0 and 2-5 match
+/// what Limine programs, so the mappings it made (including its write-combining framebuffer
+/// mapping, which uses entry 5) keep their memory types.
+const PAT_VALUE: u64 = 0x0407_0105_0007_0106;
+
+pub fn init_pat() {
+    unsafe {
+        core::arch::asm!("wbinvd", options(nostack));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+    }
+    tlb::flush_all();
+}
+
+fn cache_flags(cache: CacheMode) -> PageTableFlags {
+    match cache {
+        CacheMode::WriteBack => PageTableFlags::empty(),
+        CacheMode::WriteThrough => PAT_4KIB | PageTableFlags::WRITE_THROUGH,
+        CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+        _ => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+    }
+}
+
+fn protection_flags(prot: Protection) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if prot.contains(Protection::WRITE) {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !prot.contains(Protection::EXEC) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn map_error<S: PageSize>(e: MapToError<S>) -> MapError {
+    match e {
+        MapToError::FrameAllocationFailed => MapError::OutOfMemory,
+        MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+            MapError::AlreadyMapped
+        }
+    }
+}
+
+fn check_range(virt: VirtAddr, size: usize) -> Result<(), MapError> {
+    check_size(size)?;
+    if virt.is_aligned(PAGE_SIZE as u64) {
+        Ok(())
+    } else {
+        Err(MapError::InvalidArgument)
+    }
+}
+
+/// The largest page that can map `phys` at `virt` without going past `remaining` bytes.
+fn largest_page(virt: VirtAddr, phys: PhysAddr, remaining: usize, flags: PageTableFlags) -> usize {
+    if flags.contains(PAT_4KIB) {
+        return PAGE_SIZE;
+    }
+    [GIANT_PAGE_SIZE, HUGE_PAGE_SIZE]
+        .into_iter()
+        .filter(|&size| size != GIANT_PAGE_SIZE || *HAS_GIANT_PAGES)
+        .find(|&size| {
+            remaining >= size && virt.is_aligned(size as u64) && phys.is_aligned(size as u64)
+        })
+        .unwrap_or(PAGE_SIZE)
+}
+
+unsafe fn map_page<S: PageSize>(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapError>
+where
+    ActiveMapper: Mapper<S>,
+{
+    unsafe {
+        mapper.map_to_with_table_flags(
+            Page::<S>::containing_address(virt),
+            PhysFrame::containing_address(phys),
+            flags,
+            TABLE_FLAGS,
+            &mut GlobalFrameAllocator,
+        )
+    }
+    .map(|flush| flush.flush())
+    .map_err(map_error)
+}
+
+unsafe fn map_sized(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    unsafe {
+        match size {
+            GIANT_PAGE_SIZE => map_page::<Size1GiB>(mapper, virt, phys, flags),
+            HUGE_PAGE_SIZE => map_page::<Size2MiB>(mapper, virt, phys, flags),
+            _ => map_page::<Size4KiB>(mapper, virt, phys, flags),
+        }
+    }
+}
+
+fn unmap_page<S: PageSize>(mapper: &mut ActiveMapper, virt: VirtAddr) -> Option<PhysFrameRange>
+where
+    ActiveMapper: Mapper<S>,
+{
+    let (frame, flush) = mapper.unmap(Page::<S>::containing_address(virt)).ok()?;
+    flush.flush();
+    let start = PhysFrame::containing_address(frame.start_address());
+    Some(PhysFrame::range(start, start + S::SIZE / Size4KiB::SIZE))
+}
+
+fn unmap_sized(mapper: &mut ActiveMapper, virt: VirtAddr, size: usize) -> Option<PhysFrameRange> {
+    match size {
+        GIANT_PAGE_SIZE => unmap_page::<Size1GiB>(mapper, virt),
+        HUGE_PAGE_SIZE => unmap_page::<Size2MiB>(mapper, virt),
+        _ => unmap_page::<Size4KiB>(mapper, virt),
+    }
+}
+
+unsafe fn update_page<S: PageSize>(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapError>
+where
+    ActiveMapper: Mapper<S>,
+{
+    unsafe { mapper.update_flags(Page::<S>::containing_address(virt), flags) }
+        .map(|flush| flush.flush())
+        .map_err(|_| MapError::NotMapped)
+}
+
+unsafe fn update_sized(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    unsafe {
+        match size {
+            GIANT_PAGE_SIZE => update_page::<Size1GiB>(mapper, virt, flags),
+            HUGE_PAGE_SIZE => update_page::<Size2MiB>(mapper, virt, flags),
+            _ => update_page::<Size4KiB>(mapper, virt, flags),
+        }
+    }
+}
+
+/// Replaces the huge page of `page_size` bytes containing `virt` with a table of the next smaller
+/// page size, mapping the same frames with the same flags and memory type.
+///
+/// The loader never maps huge pages with the PAT bit (see [`largest_page`]), but Limine does, for
+/// its write-combining framebuffer mapping in the HHDM. In a huge page entry the PAT bit is bit 12
+/// of the address, so it is masked off the frame address and moved to [`PAT_4KIB`] when splitting
+/// down to 4 KiB pages.
+unsafe fn split_huge_page(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    page_size: usize,
+) -> Result<(), MapError> {
+    let (depth, child_size) = match page_size {
+        GIANT_PAGE_SIZE => (2, HUGE_PAGE_SIZE),
+        HUGE_PAGE_SIZE => (3, PAGE_SIZE),
+        _ => panic!("cannot split a {page_size:#X} byte page"),
+    };
+    let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index()];
+
+    let mut table = mapper.level_4_table_mut();
+    for &index in &indices[..depth - 1] {
+        let frame = table[index].frame().map_err(|_| MapError::NotMapped)?;
+        table = unsafe { &mut *FrameMappping.frame_to_pointer(frame) };
+    }
+    let entry = &mut table[indices[depth - 1]];
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) {
+        return Err(MapError::NotMapped);
+    }
+
+    let pat = entry.addr().as_u64() & PAT_HUGE;
+    let base = entry.addr().align_down(page_size as u64);
+    let (child_flags, child_pat) = if child_size != PAGE_SIZE {
+        (flags, pat)
+    } else if pat != 0 {
+        ((flags - PageTableFlags::HUGE_PAGE) | PAT_4KIB, 0)
+    } else {
+        (flags - PageTableFlags::HUGE_PAGE, 0)
+    };
+    let table_frame = GlobalFrameAllocator
+        .allocate_frame()
+        .ok_or(MapError::OutOfMemory)?;
+    let child_table = unsafe { &mut *FrameMappping.frame_to_pointer(table_frame) };
+    for (i, child) in child_table.iter_mut().enumerate() {
+        child.set_addr(base + (i * child_size) as u64 + child_pat, child_flags);
+    }
+    entry.set_frame(table_frame, TABLE_FLAGS);
+    tlb::flush(virt);
+    Ok(())
+}
+
+/// Walks `[virt, virt + size)` one mapped page at a time, first splitting any huge page that
+/// straddles either end of the range. `f` is called with each page and its size. Holes are skipped,
+/// but reported as [`MapError::NotMapped`] once the walk is done.
+unsafe fn for_each_page(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    size: usize,
+    mut f: impl FnMut(&mut ActiveMapper, VirtAddr, usize, PageTableFlags) -> Result<(), MapError>,
+) -> Result<(), MapError> {
+    let end = virt + size as u64;
+    let mut addr = virt;
+    let mut res = Ok(());
+    while addr < end {
+        let TranslateResult::Mapped { frame, flags, .. } = mapper.translate(addr) else {
+            res = Err(MapError::NotMapped);
+            addr += PAGE_SIZE as u64;
+            continue;
+        };
+        let page_size = frame.size() as usize;
+        if !addr.is_aligned(page_size as u64) || ((end - addr) as usize) < page_size {
+            unsafe { split_huge_page(mapper, addr, page_size)? };
+            continue;
+        }
+        f(mapper, addr, page_size, flags)?;
+        addr += page_size as u64;
+    }
+    res
+}
+
+/// Unmaps every page in the range, optionally returning the backing frames to the frame allocator.
+/// Carries on past holes, but reports them.
+unsafe fn unmap_pages(
+    mapper: &mut ActiveMapper,
+    virt: VirtAddr,
+    size: usize,
+    free_frames: bool,
+) -> Result<(), MapError> {
+    unsafe {
+        for_each_page(mapper, virt, size, |mapper, addr, page_size, _| {
+            let frames = unmap_sized(mapper, addr, page_size).ok_or(MapError::NotMapped)?;
+            if free_frames {
+                phys::deallocate_contiguous(frames);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Maps `[phys, phys + size)` at `virt`, using 2 MiB and 1 GiB pages wherever both addresses are
+/// suitably aligned. On failure, nothing stays mapped.
+///
+/// # Safety
+/// `virt` must be reserved address space owned by the caller, and the physical range must not be
+/// memory that something else relies on being unaliased.
+pub unsafe fn map_range(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+    prot: Protection,
+    cache: CacheMode,
+) -> Result<(), MapError> {
+    check_range(virt, size)?;
+    if !phys.is_aligned(PAGE_SIZE as u64) {
+        return Err(MapError::InvalidArgument);
+    }
+
+    let flags = protection_flags(prot) | cache_flags(cache);
+    let mut mapper = PAGE_TABLE_MAPPING.lock();
+    let mut offset = 0;
+    while offset < size {
+        let (page, frame) = (virt + offset as u64, phys + offset as u64);
+        let page_size = largest_page(page, frame, size - offset, flags);
+        if let Err(e) = unsafe { map_sized(&mut mapper, page, frame, page_size, flags) } {
+            unsafe { unmap_pages(&mut mapper, virt, offset, false) }.ok();
+            return Err(e);
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Maps freshly allocated frames at `virt`, using 2 MiB pages where `virt` is aligned and
+/// contiguous memory is available. On failure, nothing stays mapped or allocated.
+///
+/// # Safety
+/// `virt` must be reserved address space owned by the caller.
+pub unsafe fn map_anonymous(virt: VirtAddr, size: usize, prot: Protection) -> Result<(), MapError> {
+    check_range(virt, size)?;
+
+    let flags = protection_flags(prot);
+    let mut mapper = PAGE_TABLE_MAPPING.lock();
+    let mut offset = 0;
+    while offset < size {
+        let page = virt + offset as u64;
+        let huge = page.is_aligned(HUGE_PAGE_SIZE as u64) && size - offset >= HUGE_PAGE_SIZE;
+        let (page_size, frames) = match huge
+            .then(|| phys::allocate_contiguous(HUGE_PAGE_SIZE / PAGE_SIZE, Zone::Any))
+            .flatten()
+        {
+            Some(frames) => (HUGE_PAGE_SIZE, Some(frames)),
+            None => (PAGE_SIZE, phys::allocate_contiguous(1, Zone::Any)),
+        };
+        let res = match frames {
+            Some(frames) => unsafe {
+                map_sized(
+                    &mut mapper,
+                    page,
+                    frames.start.start_address(),
+                    page_size,
+                    flags,
+                )
+                .inspect_err(|_| phys::deallocate_contiguous(frames))
+            },
+            None => Err(MapError::OutOfMemory),
+        };
+        if let Err(e) = res {
+            unsafe { unmap_pages(&mut mapper, virt, offset, true) }.ok();
+            return Err(e);
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// # Safety
+/// Nothing may access the range afterwards.
+pub unsafe fn unmap_range(virt: VirtAddr, size: usize) -> Result<(), MapError> {
+    check_range(virt, size)?;
+    unsafe { unmap_pages(&mut PAGE_TABLE_MAPPING.lock(), virt, size, false) }
+}
+
+/// Unmaps a range mapped with [`map_anonymous`] and frees its frames.
+///
+/// # Safety
+/// Nothing may access the range afterwards.
+pub unsafe fn unmap_anonymous(virt: VirtAddr, size: usize) -> Result<(), MapError> {
+    check_range(virt, size)?;
+    unsafe { unmap_pages(&mut PAGE_TABLE_MAPPING.lock(), virt, size, true) }
+}
+
+/// Changes the permissions of every page in the range, keeping its caching attributes. Huge pages
+/// that only partly overlap the range are split first.
+///
+/// # Safety
+/// Removing permissions from memory that is still in use is undefined behaviour.
+pub unsafe fn protect_range(virt: VirtAddr, size: usize, prot: Protection) -> Result<(), MapError> {
+    check_range(virt, size)?;
+    let mut mapper = PAGE_TABLE_MAPPING.lock();
+    unsafe {
+        for_each_page(&mut mapper, virt, size, |mapper, addr, page_size, flags| {
+            let new_flags =
+                protection_flags(prot) | (flags & (CACHE_FLAGS | PageTableFlags::GLOBAL));
+            update_sized(mapper, addr, page_size, new_flags)
+        })
+    }
+}
+
+/// How many pages of each size back a range, for debugging output.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MappingSummary {
+    pub pages_4kib: usize,
+    pub pages_2mib: usize,
+    pub pages_1gib: usize,
+    /// 4 KiB pages of the range that aren't mapped at all
+    pub unmapped: usize,
+}
+
+impl core::fmt::Display for MappingSummary {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{} x 1 GiB, {} x 2 MiB, {} x 4 KiB",
+            self.pages_1gib, self.pages_2mib, self.pages_4kib
+        )?;
+        if self.unmapped != 0 {
+            write!(f, " ({} x 4 KiB unmapped)", self.unmapped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the pages of each size mapping `[virt, virt + size)`, without modifying anything.
+pub fn summarize(virt: VirtAddr, size: usize) -> MappingSummary {
+    let mapper = PAGE_TABLE_MAPPING.lock();
+    let mut summary = MappingSummary::default();
+    let end = virt + size as u64;
+    let mut addr = virt.align_down(PAGE_SIZE as u64);
+    while addr < end {
+        let page_size = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => {
+                match frame {
+                    MappedFrame::Size1GiB(_) => summary.pages_1gib += 1,
+                    MappedFrame::Size2MiB(_) => summary.pages_2mib += 1,
+                    MappedFrame::Size4KiB(_) => summary.pages_4kib += 1,
+                }
+                frame.size()
+            }
+            _ => {
+                summary.unmapped += 1;
+                PAGE_SIZE as u64
+            }
+        };
+        addr = addr.align_down(page_size) + page_size;
+    }
+    summary
+}
+
+/// Whether `addr` is mapped, or `None` if the page tables are locked. Never waits, so it is safe to
+/// use while panicking.
+pub fn is_mapped(addr: VirtAddr) -> Option<bool> {
+    let mapper = PAGE_TABLE_MAPPING.try_lock()?;
+    Some(matches!(
+        mapper.translate(addr),
+        TranslateResult::Mapped { .. }
+    ))
+}
+
+/// Maps a physical range that isn't managed by the frame allocator (device memory, firmware
+/// tables) read-write into the MMIO region. `phys` need not be page aligned; the returned address
+/// corresponds to `phys` itself.
+///
+/// # Safety
+/// See [`map_range`].
+pub unsafe fn map_mmio(
+    phys: PhysAddr,
+    size: usize,
+    cache: CacheMode,
+) -> Result<VirtAddr, MapError> {
+    let offset = phys.as_u64() % PAGE_SIZE as u64;
+    let len = (offset as usize + size).next_multiple_of(PAGE_SIZE);
+    let phys_base = phys.align_down(PAGE_SIZE as u64);
+    // Align the virtual base like the physical one, so large ranges such as framebuffers get huge
+    // pages
+    let align = [GIANT_PAGE_SIZE, HUGE_PAGE_SIZE]
+        .into_iter()
+        .find(|&size| len >= size && phys_base.is_aligned(size as u64))
+        .unwrap_or(PAGE_SIZE);
+    let base = reserve(AddressRegion::Mmio, len, align)?;
+    if let Err(e) = unsafe { map_range(base, phys_base, len, Protection::WRITE, cache) } {
+        unsafe { release(AddressRegion::Mmio, base, len) }.expect("MMIO range was just reserved");
+        return Err(e);
+    }
+    Ok(base + offset)
+}
+
+/// # Safety
+/// `virt` and `size` must be exactly as passed to and returned from [`map_mmio`], and nothing may
+/// access the mapping afterwards.
+pub unsafe fn unmap_mmio(virt: VirtAddr, size: usize) -> Result<(), MapError> {
+    let offset = virt.as_u64() % PAGE_SIZE as u64;
+    let base = virt.align_down(PAGE_SIZE as u64);
+    let len = (offset as usize + size).next_multiple_of(PAGE_SIZE);
+    unsafe {
+        unmap_range(base, len)?;
+        release(AddressRegion::Mmio, base, len)
+    }
+}
+
+/// Checks an address a module passed in. `VirtAddr::from_ptr` panics on non-canonical addresses,
+/// and a module mustn't be able to bring the loader down with one.
+fn virt_arg(virt: *mut c_void) -> Result<VirtAddr, MapError> {
+    VirtAddr::try_new(virt as u64).map_err(|_| MapError::InvalidArgument)
+}
+
+/// Like [`virt_arg`], for physical addresses wider than `PhysAddr::new` accepts.
+fn phys_arg(phys: u64) -> Result<PhysAddr, MapError> {
+    PhysAddr::try_new(phys).map_err(|_| MapError::InvalidArgument)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_reserve_virtual(
+    region: AddressRegion,
+    size: usize,
+    align: usize,
+    addr: *mut *mut c_void,
+) -> u32 {
+    MapError::into_status(reserve(region, size, align).map(|virt| unsafe {
+        addr.write(virt.as_mut_ptr());
+    }))
+}
+los_api::export_symbol!(kernel_reserve_virtual, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_release_virtual(
+    region: AddressRegion,
+    addr: *mut c_void,
+    size: usize,
+) -> u32 {
+    MapError::into_status(virt_arg(addr).and_then(|addr| unsafe { release(region, addr, size) }))
+}
+los_api::export_symbol!(kernel_release_virtual, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_map_range(
+    virt: *mut c_void,
+    phys: u64,
+    size: usize,
+    prot: Protection,
+    cache: CacheMode,
+) -> u32 {
+    let (virt, phys) = match (virt_arg(virt), phys_arg(phys)) {
+        (Ok(virt), Ok(phys)) => (virt, phys),
+        _ => return MapError::into_status(Err(MapError::InvalidArgument)),
+    };
+    MapError::into_status(unsafe { map_range(virt, phys, size, prot, cache) })
+}
+los_api::export_symbol!(kernel_map_range, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_unmap_range(virt: *mut c_void, size: usize) -> u32 {
+    MapError::into_status(virt_arg(virt).and_then(|virt| unsafe { unmap_range(virt, size) }))
+}
+los_api::export_symbol!(kernel_unmap_range, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_protect_range(virt: *mut c_void, size: usize, prot: Protection) -> u32 {
+    MapError::into_status(
+        virt_arg(virt).and_then(|virt| unsafe { protect_range(virt, size, prot) }),
+    )
+}
+los_api::export_symbol!(kernel_protect_range, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_map_mmio(
+    phys: u64,
+    size: usize,
+    cache: CacheMode,
+    addr: *mut *mut c_void,
+) -> u32 {
+    let virt = phys_arg(phys).and_then(|phys| unsafe { map_mmio(phys, size, cache) });
+    MapError::into_status(virt.map(|virt| unsafe {
+        addr.write(virt.as_mut_ptr());
+    }))
+}
+los_api::export_symbol!(kernel_map_mmio, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_unmap_mmio(virt: *mut c_void, size: usize) -> u32 {
+    MapError::into_status(virt_arg(virt).and_then(|virt| unsafe { unmap_mmio(virt, size) }))
+}
+los_api::export_symbol!(kernel_unmap_mmio, 1);
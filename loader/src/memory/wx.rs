@@ -0,0 +1,126 @@
+//! Enforcement of W^X: no page is ever both writable and executable.
+//!
+//! Limine maps the loader with whatever permissions it chooses, and the HHDM as executable, so the
+//! loader tightens both itself. [`report_wx_mappings`] then checks the result.
+
+use los_api::println;
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{PageTable, PageTableFlags, mapper::PageTableFrameMapping},
+};
+
+use super::{FrameMappping, PAGE_TABLE_MAPPING, virt};
+use crate::{
+    boot_info::{boot_info, hhdm_offset},
+    elf::{PT_LOAD, Phdr},
+    loader::{segment_pages, segment_protection},
+};
+
+/// Bytes mapped by one entry of a table at each level, indexed by level
+const ENTRY_SIZE: [u64; 5] = [0, 1 << 12, 1 << 21, 1 << 30, 1 << 39];
+
+/// Sets `EFER.NXE`, without which [`PageTableFlags::NO_EXECUTE`] is a reserved bit.
+pub fn enable_nx() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) }
+}
+
+/// Maps each segment of the loader image at `base` with the permissions from its program header:
+/// text read-only and executable, rodata read-only, data read-write, and everything but text
+/// non-executable.
+///
+/// # Safety
+/// `phdrs` must be the program headers the loader was loaded from.
+pub unsafe fn protect_loader(base: VirtAddr, phdrs: &[Phdr]) {
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+        let (start, size) = segment_pages(base.as_u64() as usize, phdr);
+        unsafe { virt::protect_range(start, size, segment_protection(phdr)) }
+            .expect("failed to protect loader segment");
+    }
+}
+
+/// Marks the top-level entries covering the HHDM non-executable. Nothing runs from the HHDM, and
+/// the bit at the top level covers every mapping beneath it, including ones made later.
+pub fn harden_hhdm() {
+    let phys_end = boot_info()
+        .memory_map
+        .iter()
+        .map(|region| region.base + region.length)
+        .max()
+        .unwrap_or(0);
+    let first = VirtAddr::new(hhdm_offset()).p4_index();
+    let last = VirtAddr::new(hhdm_offset() + phys_end.max(1) - 1).p4_index();
+    let loader = VirtAddr::from_ptr(ld_so_impl::load_addr()).p4_index();
+
+    let mut mapper = PAGE_TABLE_MAPPING.lock();
+    let level_4_table = mapper.level_4_table_mut();
+    for idx in u16::from(first)..=u16::from(last) {
+        let entry = &mut level_4_table[idx as usize];
+        if idx == u16::from(loader) || !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// Collects the writable and executable leaf mappings under `table`, merging adjacent ones.
+/// `writable` and `executable` are the permissions granted by the entries above it.
+fn scan_table(
+    table: &PageTable,
+    level: usize,
+    base: u64,
+    writable: bool,
+    executable: bool,
+    found: &mut impl FnMut(u64, u64),
+) {
+    for (idx, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = VirtAddr::new_truncate(base + idx as u64 * ENTRY_SIZE[level]).as_u64();
+        let writable = writable && flags.contains(PageTableFlags::WRITABLE);
+        let executable = executable && !flags.contains(PageTableFlags::NO_EXECUTE);
+        if !writable || !executable {
+            continue;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            found(addr, ENTRY_SIZE[level]);
+        } else if let Ok(frame) = entry.frame() {
+            let child = unsafe { &*FrameMappping.frame_to_pointer(frame) };
+            scan_table(child, level - 1, addr, writable, executable, found);
+        }
+    }
+}
+
+/// Walks the active page tables and reports every range that is mapped both writable and
+/// executable. Returns the number of such ranges.
+pub fn report_wx_mappings() -> usize {
+    let mapper = PAGE_TABLE_MAPPING.lock();
+    let mut count = 0;
+    let mut current: Option<(u64, u64)> = None;
+    let mut report = |(start, end): (u64, u64)| {
+        println!("W^X violation: [{start:#018X} - {end:#018X}) is writable and executable");
+        count += 1;
+    };
+    scan_table(
+        mapper.level_4_table(),
+        4,
+        0,
+        true,
+        true,
+        &mut |addr, size| match &mut current {
+            Some((_, end)) if *end == addr => *end += size,
+            _ => {
+                if let Some(range) = current.replace((addr, addr + size)) {
+                    report(range);
+                }
+            }
+        },
+    );
+    if let Some(range) = current {
+        report(range);
+    }
+    count
+}
@@ -0,0 +1,118 @@
+//! Verification of the signatures `sign-module.sh` appends to kernel modules.
+//!
+//! A signed module is the module file followed by a [`SigTrailer`]: an Ed25519 signature over the
+//! 64-byte SHAKE256 digest of everything before the trailer, then [`SIG_MAGIC`].
+//!
+//! The digest comes from `lc-crypto`, which has no Ed25519 implementation, so the signature itself
+//! is checked with `ed25519-dalek`.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use lc_crypto::{
+    digest::{ContinuousOutputDigest, RawDigest},
+    raw_shake256,
+    traits::ByteArray,
+};
+use los_api::{cmdline::Value, println};
+use spin::Lazy;
+
+use crate::boot_info::{BootModule, boot_info};
+
+pub const SIG_MAGIC: [u8; 16] = *b"LILIUM MODSIG 1\n";
+
+/// Raw Ed25519 public key embedded by `build.rs`, empty if none was configured.
+static MODULE_KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/module-signing.pub"));
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigTrailer {
+    pub signature: [u8; 64],
+    pub magic: [u8; 16],
+}
+
+/// What to do with modules whose signature is missing or doesn't verify, selected with
+/// `--module-sig enforce|warn` on the kernel command line. The default is [`SigMode::Warn`], so
+/// unsigned modules still load unless signatures are enforced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigMode {
+    /// Refuse to load them
+    Enforce,
+    /// Load them anyway, but say so
+    Warn,
+}
+
+pub static SIG_MODE: Lazy<SigMode> = Lazy::new(|| match boot_info().options().get("module-sig") {
+    Some(Ok(Value::Enum("warn"))) | None => SigMode::Warn,
+    Some(Ok(_)) => SigMode::Enforce,
+    Some(Err(e)) => {
+        println!("{e}, enforcing signatures");
+        SigMode::Enforce
+    }
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigError {
+    /// The module has no signature trailer
+    Unsigned,
+    /// The loader was built without a module signing key
+    NoKey,
+    /// The signature doesn't match the module or the key
+    BadSignature,
+}
+
+/// The 64-byte SHAKE256 digest of `data`.
+fn digest(data: &[u8]) -> [u8; 64] {
+    let mut shake = <raw_shake256!(512)>::default();
+    let chunks = ByteArray::array_chunks(data);
+    let rem = chunks.remainder();
+    for chunk in chunks {
+        shake.raw_update(chunk).unwrap();
+    }
+    shake.raw_update_final(rem).unwrap();
+    shake.next_output().unwrap()
+}
+
+/// Splits a module file into its contents and signature trailer, if it has one.
+pub fn split_signature(data: &[u8]) -> Option<(&[u8], SigTrailer)> {
+    let split = data.len().checked_sub(size_of::<SigTrailer>())?;
+    let (contents, trailer) = data.split_at(split);
+    let (signature, magic) = trailer.split_at(64);
+    let trailer = SigTrailer {
+        signature: signature.try_into().unwrap(),
+        magic: magic.try_into().unwrap(),
+    };
+    (trailer.magic == SIG_MAGIC).then_some((contents, trailer))
+}
+
+pub fn verify(data: &[u8]) -> Result<(), SigError> {
+    let (contents, trailer) = split_signature(data).ok_or(SigError::Unsigned)?;
+    let key = <[u8; 32]>::try_from(MODULE_KEY).map_err(|_| SigError::NoKey)?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| SigError::NoKey)?;
+    key.verify_strict(
+        &digest(contents),
+        &Signature::from_bytes(&trailer.signature),
+    )
+    .map_err(|_| SigError::BadSignature)
+}
+
+/// Checks the signature of `module` against [`SIG_MODE`], reporting any problem. Returns whether
+/// the module may be loaded. The check only runs the first time, as the dynamic linker asks again
+/// for every module that depends on this one.
+pub fn check_module(module: &BootModule) -> bool {
+    *module.signature_ok.call_once(|| match verify(module.data) {
+        Ok(()) => true,
+        Err(e) if *SIG_MODE == SigMode::Warn => {
+            println!(
+                "Loading {} despite its signature check failing: {e:?}",
+                module.name().display()
+            );
+            true
+        }
+        Err(e) => {
+            println!(
+                "Refusing {}: module signature check failed: {e:?}",
+                module.name().display()
+            );
+            false
+        }
+    })
+}
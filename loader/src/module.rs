@@ -0,0 +1,679 @@
+//! The registry of kernel modules loaded from the Limine module list, and their initialization.
+
+use alloc::{
+    ffi::CString,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::ffi::{CStr, c_char};
+
+use los_api::{
+    cmdline::{CMDLINE_SECTION, CommandLine, OptionSpec},
+    ksym::SymbolVersion,
+    module::{
+        ABI_VERSION, DependencyKind, MODINFO_MAGIC, MODINFO_SECTION, ModInfo, ParamLookup,
+        UnloadError,
+    },
+    println,
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::{
+    boot_info::{BootModule, LOADER_OPTIONS, boot_info},
+    elf::{
+        DT_FINI, DT_FINI_ARRAY, DT_FINI_ARRAYSZ, DT_INIT, DT_INIT_ARRAY, DT_INIT_ARRAYSZ, ElfFile,
+        R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, Rela, SymbolTable,
+    },
+    hpet,
+    interrupt::irq,
+    ksym, loader, timer,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ModuleState {
+    /// Accepted, but not loaded yet
+    Pending,
+    /// Mapped and linked, but not initialized yet
+    Loaded,
+    /// Initialized successfully
+    Live,
+    /// Refused, or failed to load or initialize, possibly because a dependency did
+    Failed,
+    /// Unloaded, or being unloaded
+    Unloaded,
+}
+
+impl ModuleState {
+    /// Whether the module will never be loaded or initialized (again)
+    fn is_gone(self) -> bool {
+        matches!(self, Self::Failed | Self::Unloaded)
+    }
+}
+
+/// Something a module needs before it can be loaded or initialized.
+pub struct Requirement {
+    pub name: CString,
+    pub kind: DependencyKind,
+    /// Declared in the module info, rather than a `DT_NEEDED` entry. Undeclared requirements on
+    /// objects that aren't modules (the loader itself) are left to the dynamic linker.
+    pub declared: bool,
+}
+
+pub struct LoadedModule {
+    pub boot: &'static BootModule,
+    pub info: Option<ModInfo>,
+    pub base: Option<VirtAddr>,
+    /// Size of the image's address space reservation
+    pub size: usize,
+    /// Parameters from the module string, with lowercase keys
+    pub params: Vec<(String, Option<String>)>,
+    pub requirements: Vec<Requirement>,
+    pub state: ModuleState,
+    /// Indices of the modules this one imports symbols from or hard depends on
+    pub providers: Vec<usize>,
+    /// Number of loaded modules whose `providers` include this one. A module can only be unloaded
+    /// once nothing refers to it anymore.
+    pub refcount: usize,
+    /// The module's functions, for backtraces
+    pub symbols: Option<SymbolTable>,
+}
+
+impl LoadedModule {
+    /// The name from the module info, or the file name for modules without one.
+    pub fn name(&self) -> &CStr {
+        match &self.info {
+            Some(info) if !info.name().is_empty() => info.name(),
+            _ => self.boot.name(),
+        }
+    }
+
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.base
+            .is_some_and(|base| addr >= base && addr < base + self.size as u64)
+    }
+
+    /// Dependencies may refer to a module either by its name or by its file name.
+    fn answers_to(&self, name: &CStr) -> bool {
+        self.name() == name || self.boot.name() == name
+    }
+}
+
+static MODULES: Mutex<Vec<LoadedModule>> = Mutex::new(Vec::new());
+
+fn read_modinfo(elf: &ElfFile) -> Result<Option<ModInfo>, String> {
+    let Some(bytes) = elf.section_by_name(MODINFO_SECTION) else {
+        return Ok(None);
+    };
+    if bytes.len() < size_of::<ModInfo>() {
+        return Err("truncated module info".to_string());
+    }
+    let info = unsafe { bytes.as_ptr().cast::<ModInfo>().read_unaligned() };
+    if info.magic != MODINFO_MAGIC {
+        return Err("corrupt module info".to_string());
+    }
+    if info.abi_version != ABI_VERSION {
+        return Err(format!(
+            "built for los-api ABI version {}, but this loader provides version {ABI_VERSION}",
+            info.abi_version
+        ));
+    }
+    Ok(Some(info))
+}
+
+/// Splits a module string into `key=value` parameters and bare `key` flags, separated by
+/// whitespace. Values may be double-quoted to include whitespace. Keys are lowercased, since they
+/// are matched case-insensitively.
+fn parse_params(string: &str) -> Vec<(String, Option<String>)> {
+    let mut params = Vec::new();
+    let mut chars = string.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        let value = chars.next_if_eq(&'=').map(|_| {
+            let mut value = String::new();
+            let mut quoted = false;
+            while let Some(c) = chars.next_if(|&c| quoted || !c.is_whitespace()) {
+                if c == '"' {
+                    quoted = !quoted;
+                } else {
+                    value.push(c);
+                }
+            }
+            value
+        });
+        params.push((key.to_ascii_lowercase(), value));
+    }
+    params
+}
+
+struct Inspected {
+    info: Option<ModInfo>,
+    size: usize,
+    requirements: Vec<Requirement>,
+}
+
+/// Reads the module info and requirements of a module file, or explains why it can't be loaded.
+fn inspect(boot: &BootModule) -> Result<Inspected, String> {
+    let elf = ElfFile::parse(boot.data).ok_or("not a shared object for this machine")?;
+    let info = read_modinfo(&elf)?;
+    let size = elf
+        .phdrs()
+        .as_deref()
+        .and_then(loader::image_size)
+        .ok_or("no loadable segments")?;
+
+    let needed = elf.needed().into_iter().map(|name| Requirement {
+        name: CString::from(name),
+        kind: DependencyKind::Hard,
+        declared: false,
+    });
+    let declared = info
+        .iter()
+        .flat_map(ModInfo::dependencies)
+        .map(|dep| Requirement {
+            name: CString::from(dep.name()),
+            kind: dep.kind,
+            declared: true,
+        });
+    let requirements = needed.chain(declared).collect();
+    Ok(Inspected {
+        info,
+        size,
+        requirements,
+    })
+}
+
+enum Readiness {
+    Ready,
+    /// Waiting on a dependency that hasn't got there yet
+    Waiting,
+    /// A hard dependency failed or is missing, so this module never will be ready
+    Unsatisfied {
+        name: CString,
+        missing: bool,
+    },
+}
+
+/// Checks whether every dependency of `modules[idx]` has reached `target`.
+fn readiness(modules: &[LoadedModule], idx: usize, target: ModuleState) -> Readiness {
+    for req in &modules[idx].requirements {
+        let hard = req.kind == DependencyKind::Hard;
+        match modules.iter().find(|m| m.answers_to(&req.name)) {
+            Some(dep) if dep.state.is_gone() => {
+                if hard {
+                    return Readiness::Unsatisfied {
+                        name: req.name.clone(),
+                        missing: false,
+                    };
+                }
+            }
+            Some(dep) if dep.state < target => return Readiness::Waiting,
+            Some(_) => {}
+            None if hard && req.declared => {
+                return Readiness::Unsatisfied {
+                    name: req.name.clone(),
+                    missing: true,
+                };
+            }
+            None => {}
+        }
+    }
+    Readiness::Ready
+}
+
+/// Moves every module in state `from` to `target` by calling `step` on it, each only after its
+/// dependencies have reached `target`. `step` runs without the registry locked, since module code
+/// may call back into the loader; it returns the module's base address, or `None` on failure.
+///
+/// Modules that can't be moved because of a dependency are reported with `verb` and marked failed.
+fn in_dependency_order(
+    from: ModuleState,
+    target: ModuleState,
+    verb: &str,
+    mut step: impl FnMut(&CStr, &'static BootModule, Option<VirtAddr>) -> Option<VirtAddr>,
+) {
+    loop {
+        let (idx, name, boot, base) = {
+            let mut modules = MODULES.lock();
+            let mut next = None;
+            for idx in 0..modules.len() {
+                if modules[idx].state != from {
+                    continue;
+                }
+                match readiness(&modules, idx, target) {
+                    Readiness::Ready => {
+                        next = Some(idx);
+                        break;
+                    }
+                    Readiness::Waiting => {}
+                    Readiness::Unsatisfied { name, missing } => {
+                        println!(
+                            "Not {verb} {}: dependency {} {}",
+                            modules[idx].name().display(),
+                            name.display(),
+                            if missing { "is missing" } else { "failed" }
+                        );
+                        modules[idx].state = ModuleState::Failed;
+                    }
+                }
+            }
+
+            let Some(idx) = next else {
+                for module in modules.iter_mut().filter(|m| m.state == from) {
+                    println!(
+                        "Not {verb} {}: circular dependency",
+                        module.name().display()
+                    );
+                    module.state = ModuleState::Failed;
+                }
+                break;
+            };
+            let module = &modules[idx];
+            (idx, CString::from(module.name()), module.boot, module.base)
+        };
+
+        let res = step(&name, boot, base);
+        let module = &mut MODULES.lock()[idx];
+        match res {
+            Some(base) => {
+                module.base = Some(base);
+                module.state = target;
+            }
+            None => module.state = ModuleState::Failed,
+        }
+    }
+}
+
+/// The symbols modules may bind to: those the loader exports, then those exported by the modules
+/// loaded so far, in the order the dynamic linker searches them.
+fn exported_symbols() -> Vec<SymbolVersion> {
+    let mut exports = ksym::loader_exports().to_vec();
+    for module in MODULES.lock().iter() {
+        if module.base.is_some()
+            && !module.state.is_gone()
+            && let Some(elf) = ElfFile::parse(module.boot.data)
+        {
+            exports.extend(ksym::exports(&elf));
+        }
+    }
+    exports
+}
+
+/// Checks the symbols `boot` imports against [`exported_symbols`], reporting any that aren't
+/// available. Returns whether the module may be loaded.
+///
+/// [`loader::RawPageLoader`] runs this on every module before mapping it, including those the
+/// dynamic linker pulls in as `DT_NEEDED` dependencies.
+pub fn imports_available(boot: &BootModule) -> bool {
+    let Some(elf) = ElfFile::parse(boot.data) else {
+        // Not for us to report; loading it fails anyway
+        return true;
+    };
+    let problems = ksym::check_imports(&elf, &exported_symbols());
+    for problem in &problems {
+        println!("{} {problem}", boot.name().display());
+    }
+    if !problems.is_empty() {
+        println!(
+            "Refusing {}: it imports symbols that aren't available",
+            boot.name().display()
+        );
+    }
+    problems.is_empty()
+}
+
+/// Calls `f` with the loaded module whose image contains `addr`. Gives up rather than waiting if
+/// the module list is locked, so it is safe to use while panicking.
+pub fn with_module_at<R>(addr: VirtAddr, f: impl FnOnce(&LoadedModule) -> R) -> Option<R> {
+    let modules = MODULES.try_lock()?;
+    modules
+        .iter()
+        .find(|m| !m.state.is_gone() && m.contains(addr))
+        .map(f)
+}
+
+/// Loads and links every module Limine loaded for us, each after the modules it depends on.
+/// Modules that are refused or fail to load are reported and left out.
+pub fn load_all() {
+    {
+        let mut modules = MODULES.lock();
+        for boot in &boot_info().modules {
+            let (inspected, state) = match inspect(boot) {
+                Ok(inspected) => {
+                    if inspected.info.is_none() {
+                        println!("{} has no module info", boot.name().display());
+                    }
+                    (inspected, ModuleState::Pending)
+                }
+                Err(reason) => {
+                    println!("Refusing {}: {reason}", boot.name().display());
+                    let inspected = Inspected {
+                        info: None,
+                        size: 0,
+                        requirements: Vec::new(),
+                    };
+                    (inspected, ModuleState::Failed)
+                }
+            };
+            modules.push(LoadedModule {
+                boot,
+                info: inspected.info,
+                base: None,
+                size: inspected.size,
+                params: parse_params(&String::from_utf8_lossy(boot.string.to_bytes())),
+                requirements: inspected.requirements,
+                state,
+                providers: Vec::new(),
+                refcount: 0,
+                symbols: ElfFile::parse(boot.data).and_then(|elf| elf.symbol_table()),
+            });
+        }
+    }
+
+    in_dependency_order(
+        ModuleState::Pending,
+        ModuleState::Loaded,
+        "loading",
+        |name, boot, _| match loader::load_module(boot) {
+            Ok(base) => {
+                println!("Loaded {} at {base:p}", name.display());
+                Some(base)
+            }
+            Err(e) => {
+                println!("Failed to load {}: {e:?}", name.display());
+                None
+            }
+        },
+    );
+
+    count_references(&mut MODULES.lock());
+}
+
+/// The address a symbol relocation of an image at `base` was bound to, read back from the slot
+/// the dynamic linker filled in. Relocations that don't hold a symbol's address (TLS offsets, for
+/// one) are skipped.
+///
+/// # Safety
+/// The image must be mapped and relocated.
+unsafe fn bound_address(base: VirtAddr, rela: &Rela) -> Option<VirtAddr> {
+    let addend = match rela.kind() {
+        R_X86_64_64 => rela.r_addend,
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => 0,
+        _ => return None,
+    };
+    let slot = (base + rela.r_offset).as_ptr::<u64>();
+    let value = unsafe { slot.read_unaligned() };
+    VirtAddr::try_new(value.wrapping_sub(addend as u64)).ok()
+}
+
+/// Works out which modules each newly loaded module refers to: those its symbol relocations were
+/// actually bound to, wherever the dynamic linker found them (symbols the loader defines are
+/// searched first and refer to no module), and its loaded hard dependencies.
+fn count_references(modules: &mut [LoadedModule]) {
+    let loaded = |m: &LoadedModule| m.base.is_some() && !m.state.is_gone();
+    for idx in 0..modules.len() {
+        if modules[idx].state != ModuleState::Loaded || !modules[idx].providers.is_empty() {
+            continue;
+        }
+        let base = modules[idx].base.unwrap();
+        let elf =
+            ElfFile::parse(modules[idx].boot.data).expect("loaded module is not a valid ELF file");
+        let mut providers = Vec::new();
+        for rela in elf.symbol_relocations() {
+            let Some(target) = (unsafe { bound_address(base, &rela) }) else {
+                continue;
+            };
+            let provider = modules.iter().position(|m| loaded(m) && m.contains(target));
+            providers.extend(provider);
+        }
+        for req in &modules[idx].requirements {
+            if req.kind == DependencyKind::Hard {
+                let dep = modules
+                    .iter()
+                    .position(|m| loaded(m) && m.answers_to(&req.name));
+                providers.extend(dep);
+            }
+        }
+        providers.sort_unstable();
+        providers.dedup();
+        providers.retain(|&provider| provider != idx);
+
+        for &provider in &providers {
+            modules[provider].refcount += 1;
+        }
+        modules[idx].providers = providers;
+    }
+}
+
+/// Runs `DT_INIT`, then the `DT_INIT_ARRAY` functions, then `module_init` if the module exports
+/// it. Returns the status from `module_init`, where anything but `0` is failure.
+unsafe fn run_init(base: VirtAddr, elf: &ElfFile) -> i32 {
+    let base = base.as_u64();
+    unsafe {
+        if let Some(init) = elf.dynamic_entry(DT_INIT) {
+            core::mem::transmute::<u64, extern "C" fn()>(base + init)();
+        }
+
+        if let Some(array) = elf.dynamic_entry(DT_INIT_ARRAY) {
+            let len = elf.dynamic_entry(DT_INIT_ARRAYSZ).unwrap_or(0) as usize / size_of::<usize>();
+            // The array itself has been relocated by now, so the entries are absolute
+            let funcs = core::slice::from_raw_parts((base + array) as *const usize, len);
+            for &func in funcs
+                .iter()
+                .filter(|&&func| func != 0 && func != usize::MAX)
+            {
+                core::mem::transmute::<usize, extern "C" fn()>(func)();
+            }
+        }
+
+        match elf.symbol(c"module_init") {
+            Some(sym) => core::mem::transmute::<u64, extern "C" fn() -> i32>(base + sym.st_value)(),
+            None => 0,
+        }
+    }
+}
+
+/// Initializes every loaded module, each after the modules it depends on. A module whose
+/// initialization fails is reported by name, and so are the modules that depend on it, which are
+/// not initialized at all.
+pub fn init_all() {
+    in_dependency_order(
+        ModuleState::Loaded,
+        ModuleState::Live,
+        "initializing",
+        |name, boot, base| {
+            let base = base.expect("loaded module has no base address");
+            let elf = ElfFile::parse(boot.data).expect("loaded module is not a valid ELF file");
+            match unsafe { run_init(base, &elf) } {
+                0 => Some(base),
+                status => {
+                    println!(
+                        "Module {} failed to initialize (status {status})",
+                        name.display()
+                    );
+                    None
+                }
+            }
+        },
+    );
+}
+
+/// Warns about options on the kernel command line that neither the loader nor any module declares,
+/// and lists the declared options if asked to with `--help`.
+pub fn check_cmdline() {
+    let mut options = LOADER_OPTIONS.to_vec();
+    for module in MODULES.lock().iter() {
+        if let Some(elf) = ElfFile::parse(module.boot.data) {
+            options.extend(unsafe { elf.section_entries::<OptionSpec>(CMDLINE_SECTION) });
+        }
+    }
+
+    let cmdline = CommandLine::new(&boot_info().cmdline, &options);
+    cmdline.warn();
+    if cmdline.flag("help") {
+        println!("Kernel command line options:");
+        for spec in &options {
+            println!("  {spec}");
+        }
+    }
+}
+
+/// The module whose module string includes the `kernel` flag, with its base address, if it was
+/// loaded and initialized.
+pub fn kernel_module() -> Option<(&'static BootModule, VirtAddr)> {
+    let modules = MODULES.lock();
+    let kernel = modules.iter().find(|m| {
+        m.params
+            .iter()
+            .any(|(key, value)| key == "kernel" && value.is_none())
+    })?;
+    match (kernel.state, kernel.base) {
+        (ModuleState::Live, Some(base)) => Some((kernel.boot, base)),
+        _ => None,
+    }
+}
+
+/// Runs `module_fini`, then the `DT_FINI_ARRAY` functions in reverse order, then `DT_FINI`.
+unsafe fn run_fini(base: VirtAddr, elf: &ElfFile) {
+    let base = base.as_u64();
+    unsafe {
+        if let Some(sym) = elf.symbol(c"module_fini") {
+            core::mem::transmute::<u64, extern "C" fn()>(base + sym.st_value)();
+        }
+
+        if let Some(array) = elf.dynamic_entry(DT_FINI_ARRAY) {
+            let len = elf.dynamic_entry(DT_FINI_ARRAYSZ).unwrap_or(0) as usize / size_of::<usize>();
+            let funcs = core::slice::from_raw_parts((base + array) as *const usize, len);
+            for &func in funcs
+                .iter()
+                .rev()
+                .filter(|&&func| func != 0 && func != usize::MAX)
+            {
+                core::mem::transmute::<usize, extern "C" fn()>(func)();
+            }
+        }
+
+        if let Some(fini) = elf.dynamic_entry(DT_FINI) {
+            core::mem::transmute::<u64, extern "C" fn()>(base + fini)();
+        }
+    }
+}
+
+/// Finalizes (if it was initialized) and unmaps the module `name`, which may be its name or its
+/// file name. Fails if another loaded module still refers to it.
+///
+/// The dynamic linker keeps its own record of the object. That is harmless as long as modules are
+/// only ever loaded at boot, since nothing is linked against it afterwards.
+pub fn unload(name: &CStr) -> Result<(), UnloadError> {
+    let (idx, boot, base, size, was_live) = {
+        let mut modules = MODULES.lock();
+        let idx = modules
+            .iter()
+            .position(|m| {
+                m.answers_to(name) && m.base.is_some() && m.state != ModuleState::Unloaded
+            })
+            .ok_or(UnloadError::NotFound)?;
+
+        if modules[idx].refcount != 0 {
+            for user in modules.iter().filter(|m| m.providers.contains(&idx)) {
+                println!(
+                    "Not unloading {}: still used by {}",
+                    modules[idx].name().display(),
+                    user.name().display()
+                );
+            }
+            return Err(UnloadError::InUse);
+        }
+
+        let module = &mut modules[idx];
+        let was_live = module.state == ModuleState::Live;
+        module.state = ModuleState::Unloaded;
+        (
+            idx,
+            module.boot,
+            module.base.unwrap(),
+            module.size,
+            was_live,
+        )
+    };
+
+    let elf = ElfFile::parse(boot.data).expect("loaded module is not a valid ELF file");
+    unsafe {
+        if was_live {
+            run_fini(base, &elf);
+        }
+        // Its code is about to go away, whether or not it dropped its handles
+        let image = base.as_u64()..base.as_u64() + size as u64;
+        let count = hpet::release_in(image.clone())
+            + irq::unregister_in(image.clone())
+            + timer::unregister_in(image);
+        match count {
+            0 => {}
+            count => println!(
+                "Released {count} interrupt handlers, HPET comparators and tick callbacks left \
+                 behind by {}",
+                name.display()
+            ),
+        }
+        loader::unload_module(boot, base);
+    }
+
+    let mut modules = MODULES.lock();
+    let providers = core::mem::take(&mut modules[idx].providers);
+    for provider in providers {
+        modules[provider].refcount -= 1;
+    }
+    modules[idx].base = None;
+    println!("Unloaded {}", modules[idx].name().display());
+    Ok(())
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_unload_module(name: *const c_char) -> u32 {
+    UnloadError::into_status(unload(unsafe { CStr::from_ptr(name) }))
+}
+los_api::export_symbol!(kernel_unload_module, 1);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_module_param(
+    anchor: *const u8,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> ParamLookup {
+    let key = unsafe { core::slice::from_raw_parts(key, key_len) };
+    let modules = MODULES.lock();
+    let Some(module) = modules
+        .iter()
+        .find(|m| m.contains(VirtAddr::from_ptr(anchor)))
+    else {
+        return ParamLookup::Absent;
+    };
+    match module
+        .params
+        .iter()
+        .find(|(k, _)| k.as_bytes().eq_ignore_ascii_case(key))
+    {
+        Some((_, Some(v))) => {
+            // The strings are never modified while the module is loaded, so their buffers stay put
+            unsafe {
+                value.write(v.as_ptr());
+                value_len.write(v.len());
+            }
+            ParamLookup::Value
+        }
+        Some((_, None)) => ParamLookup::Flag,
+        None => ParamLookup::Absent,
+    }
+}
+los_api::export_symbol!(kernel_module_param, 1);
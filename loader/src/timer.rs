@@ -0,0 +1,390 @@
+//! The kernel tick, driven by the local APIC timer (see [`los_api::timer`]).
+//!
+//! At boot, the local APIC timer and the TSC are measured against the HPET, or PIT channel 2 on
+//! machines without one, unless CPUID reports the TSC frequency outright. The tick count is then
+//! read from the clocksource (see [`crate::clocksource`]), so it stays monotonic however the timer
+//! is programmed, and the timer only decides when callbacks run: every tick in periodic mode, or at
+//! armed deadlines in one-shot mode, using TSC-deadline mode where the CPU supports it and the TSC
+//! is invariant.
+
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::__cpuid,
+    ffi::c_void,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use los_api::{
+    arch::x86_64::InterruptContext,
+    timer::{TICK_HZ, TickCallback, TimerMode},
+};
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    registers::model_specific::Msr,
+};
+
+use crate::{
+    apic::{lapic, lapic_read, lapic_write},
+    clocksource,
+    hpet::{self, Hpet},
+    interrupt::InterruptIndex,
+    prelude::*,
+};
+
+// xAPIC MMIO offsets, which `lapic_read` and `lapic_write` turn into MSRs in x2APIC mode
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_ONESHOT: u32 = 0b00 << 17;
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// Divide configuration for dividing the bus clock by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Keyboard controller port B, which gates PIT channel 2 and reads back its output
+const PORT_B: u16 = 0x61;
+const CALIBRATION_MS: u64 = 10;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+struct Calibration {
+    /// Local APIC timer counts per second, after the divider
+    lapic_hz: u64,
+    tsc_hz: u64,
+    /// Whether one-shot deadlines use TSC-deadline mode, which needs an invariant TSC
+    tsc_deadline: bool,
+}
+
+static CALIBRATION: Once<Calibration> = Once::new();
+
+/// Whether the timer is in [`TimerMode::OneShot`] rather than [`TimerMode::Periodic`]
+static ONESHOT: AtomicBool = AtomicBool::new(false);
+
+/// The tick armed with [`arm_oneshot`], or `u64::MAX` if none is pending
+static DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// How far ahead the timer may be programmed in one-shot mode, see [`limit_oneshot`]
+static MAX_ONESHOT_TICKS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+struct Callback {
+    id: u64,
+    callback: TickCallback,
+    data: *mut c_void,
+}
+
+struct Callbacks(Vec<Callback>);
+
+// The `data` pointers are only handed back to the callbacks they were registered with
+unsafe impl Send for Callbacks {}
+
+/// Only locked with interrupts disabled, like the interrupt line table
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks(Vec::new()));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Whether CPUID reports an invariant TSC, which runs at a constant rate in every power state.
+pub fn invariant_tsc() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// The TSC frequency from CPUID leaf 15H, if the CPU reports both its crystal clock and ratio.
+fn cpuid_tsc_hz() -> Option<u64> {
+    if __cpuid(0).eax < 0x15 {
+        return None;
+    }
+    let leaf = __cpuid(0x15);
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+/// Counts local APIC timer counts and TSC cycles over [`CALIBRATION_MS`] measured by PIT channel 2.
+/// Interrupts must be disabled.
+unsafe fn measure_against_pit() -> (u64, u64) {
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_2 = Port::<u8>::new(PIT_CHANNEL_2);
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+    unsafe {
+        // Gate channel 2 off and keep the speaker disconnected while it is set up
+        let gate = port_b.read() & !0b11;
+        port_b.write(gate);
+        // Channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        lapic_write(TIMER_DIVIDE, DIVIDE_BY_16);
+        lapic_write(LVT_TIMER, LVT_MASKED | LVT_TIMER_ONESHOT);
+        port_b.write(gate | 1);
+        lapic_write(TIMER_INITIAL_COUNT, u32::MAX);
+        let tsc_start = rdtsc();
+        // The output goes high on terminal count
+        while port_b.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let tsc_end = rdtsc();
+        let lapic_elapsed = u32::MAX - lapic_read(TIMER_CURRENT_COUNT);
+        lapic_write(TIMER_INITIAL_COUNT, 0);
+        port_b.write(gate);
+
+        let scale = 1000 / CALIBRATION_MS;
+        (lapic_elapsed as u64 * scale, (tsc_end - tsc_start) * scale)
+    }
+}
+
+/// Counts local APIC timer counts and TSC cycles over [`CALIBRATION_MS`] measured by the HPET's
+/// main counter. Interrupts must be disabled.
+unsafe fn measure_against_hpet(hpet: &Hpet) -> (u64, u64) {
+    let count = hpet.frequency() * CALIBRATION_MS / 1000;
+    unsafe {
+        lapic_write(TIMER_DIVIDE, DIVIDE_BY_16);
+        lapic_write(LVT_TIMER, LVT_MASKED | LVT_TIMER_ONESHOT);
+        let start = hpet.counter();
+        lapic_write(TIMER_INITIAL_COUNT, u32::MAX);
+        let tsc_start = rdtsc();
+        let mut end = start;
+        while end - start < count {
+            core::hint::spin_loop();
+            end = hpet.counter();
+        }
+        let tsc_end = rdtsc();
+        let lapic_elapsed = u32::MAX - lapic_read(TIMER_CURRENT_COUNT);
+        lapic_write(TIMER_INITIAL_COUNT, 0);
+
+        // The loop may overshoot by a counter read or two, so scale by what was actually measured
+        let elapsed = (end - start) as u128;
+        let frequency = hpet.frequency() as u128;
+        (
+            (lapic_elapsed as u128 * frequency / elapsed) as u64,
+            ((tsc_end - tsc_start) as u128 * frequency / elapsed) as u64,
+        )
+    }
+}
+
+fn calibration() -> &'static Calibration {
+    CALIBRATION
+        .get()
+        .expect("the timer hasn't been calibrated yet")
+}
+
+/// The TSC frequency, measured at boot or reported by CPUID.
+pub fn tsc_hz() -> u64 {
+    calibration().tsc_hz
+}
+
+/// Ticks since the clocksource was set up.
+pub fn ticks() -> u64 {
+    (clocksource::monotonic_now() as u128 * TICK_HZ as u128 / NANOS_PER_SECOND) as u64
+}
+
+pub fn mode() -> TimerMode {
+    if ONESHOT.load(Ordering::Relaxed) {
+        TimerMode::OneShot
+    } else {
+        TimerMode::Periodic
+    }
+}
+
+pub fn set_mode(mode: TimerMode) {
+    let calibration = calibration();
+    interrupts::without_interrupts(|| unsafe {
+        let vector = InterruptIndex::Timer as u32;
+        match mode {
+            TimerMode::Periodic => {
+                lapic_write(TIMER_DIVIDE, DIVIDE_BY_16);
+                lapic_write(LVT_TIMER, vector | LVT_TIMER_PERIODIC);
+                let count = (calibration.lapic_hz / TICK_HZ).clamp(1, u32::MAX as u64);
+                lapic_write(TIMER_INITIAL_COUNT, count as u32);
+            }
+            TimerMode::OneShot => {
+                // Stops the periodic countdown; nothing fires until a deadline is armed, or the
+                // clocksource needs reading
+                lapic_write(TIMER_INITIAL_COUNT, 0);
+                if calibration.tsc_deadline {
+                    lapic_write(LVT_TIMER, vector | LVT_TIMER_TSC_DEADLINE);
+                } else {
+                    lapic_write(LVT_TIMER, vector | LVT_TIMER_ONESHOT);
+                }
+                DEADLINE.store(u64::MAX, Ordering::Relaxed);
+            }
+            _ => return,
+        }
+        ONESHOT.store(mode == TimerMode::OneShot, Ordering::Relaxed);
+        if mode == TimerMode::OneShot {
+            program_oneshot(ticks());
+        }
+    });
+}
+
+/// Programs the timer in one-shot mode for the armed deadline, or for an earlier tick if
+/// [`limit_oneshot`] requires it. Interrupts must be disabled.
+unsafe fn program_oneshot(now: u64) {
+    let limit = now.saturating_add(MAX_ONESHOT_TICKS.load(Ordering::Relaxed));
+    let next = DEADLINE.load(Ordering::Relaxed).min(limit);
+    if next == u64::MAX {
+        return;
+    }
+    let calibration = calibration();
+    // Time is kept by the clocksource, so only what remains until the deadline is measured by the
+    // timer itself
+    let remaining = (next as u128 * NANOS_PER_SECOND / TICK_HZ as u128)
+        .saturating_sub(clocksource::monotonic_now() as u128);
+    unsafe {
+        if calibration.tsc_deadline {
+            // A deadline that has already passed fires straight away
+            let cycles = remaining * calibration.tsc_hz as u128 / NANOS_PER_SECOND;
+            Msr::new(IA32_TSC_DEADLINE).write(rdtsc() + cycles as u64);
+        } else {
+            let count = remaining * calibration.lapic_hz as u128 / NANOS_PER_SECOND;
+            // A count of 0 would disarm the timer instead. One that doesn't fit fires early, and
+            // the interrupt programs the rest
+            lapic_write(TIMER_INITIAL_COUNT, count.clamp(1, u32::MAX as u128) as u32);
+        }
+    }
+}
+
+/// Interrupts once tick `deadline` is reached, in one-shot mode.
+pub fn arm_oneshot(deadline: u64) {
+    if mode() != TimerMode::OneShot {
+        return;
+    }
+    interrupts::without_interrupts(|| unsafe {
+        DEADLINE.store(deadline, Ordering::Relaxed);
+        program_oneshot(ticks());
+    });
+}
+
+/// Has the timer interrupt at least every `ticks` ticks in one-shot mode too, for a clocksource
+/// that wraps around unless a tick callback reads it that often. Deadlines further out are then
+/// reached in several steps.
+pub fn limit_oneshot(ticks: u64) {
+    MAX_ONESHOT_TICKS.fetch_min(ticks, Ordering::Relaxed);
+}
+
+/// Calibrates the timer and starts the periodic tick.
+pub fn init() {
+    let calibration = CALIBRATION.call_once(|| {
+        let (reference, (lapic_hz, measured_tsc_hz)) = interrupts::without_interrupts(|| unsafe {
+            match hpet::hpet() {
+                Some(hpet) => ("the HPET", measure_against_hpet(hpet)),
+                None => ("the PIT", measure_against_pit()),
+            }
+        });
+        let (tsc_hz, source) = match cpuid_tsc_hz() {
+            Some(hz) => (hz, "CPUID"),
+            None => (measured_tsc_hz, reference),
+        };
+        println!(
+            "Local APIC timer runs at {} kHz, TSC at {} MHz (from {source})",
+            lapic_hz / 1000,
+            tsc_hz / 1_000_000
+        );
+        Calibration {
+            lapic_hz,
+            tsc_hz,
+            tsc_deadline: __cpuid(1).ecx & (1 << 24) != 0 && invariant_tsc(),
+        }
+    });
+    if calibration.tsc_deadline {
+        println!("One-shot deadlines use TSC-deadline mode");
+    }
+    set_mode(TimerMode::Periodic);
+}
+
+/// Runs the tick callbacks. Called from the timer interrupt.
+pub fn timer_interrupt(_: &mut InterruptContext) {
+    let now = ticks();
+    for callback in &CALLBACKS.lock().0 {
+        unsafe { (callback.callback)(now, callback.data) };
+    }
+    if mode() == TimerMode::OneShot {
+        // The interrupt may have come early, to read the clocksource or because the deadline was
+        // too far out for the timer
+        if DEADLINE.load(Ordering::Relaxed) <= now {
+            DEADLINE.store(u64::MAX, Ordering::Relaxed);
+        }
+        unsafe { program_oneshot(now) };
+    }
+    unsafe {
+        lapic().end_of_interrupt();
+    }
+}
+
+pub fn register(callback: TickCallback, data: *mut c_void) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        CALLBACKS.lock().0.push(Callback { id, callback, data });
+    });
+    id
+}
+
+/// Removes the callbacks `pred` selects, returning how many there were.
+fn unregister_where(mut pred: impl FnMut(&Callback) -> bool) -> usize {
+    interrupts::without_interrupts(|| {
+        let callbacks = &mut CALLBACKS.lock().0;
+        let before = callbacks.len();
+        callbacks.retain(|callback| !pred(callback));
+        before - callbacks.len()
+    })
+}
+
+pub fn unregister(id: u64) {
+    unregister_where(|callback| callback.id == id);
+}
+
+/// Unregisters every callback whose code lies in `range`, the image of a module being unloaded.
+/// Returns how many there were.
+pub fn unregister_in(range: Range<u64>) -> usize {
+    unregister_where(|callback| range.contains(&(callback.callback as usize as u64)))
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_ticks() -> u64 {
+    ticks()
+}
+los_api::export_symbol!(kernel_ticks, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_register_tick(callback: TickCallback, data: *mut c_void) -> u64 {
+    register(callback, data)
+}
+los_api::export_symbol!(kernel_register_tick, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_unregister_tick(id: u64) {
+    unregister(id)
+}
+los_api::export_symbol!(kernel_unregister_tick, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_set_timer_mode(mode: TimerMode) {
+    set_mode(mode)
+}
+los_api::export_symbol!(kernel_set_timer_mode, 1);
+
+#[unsafe(no_mangle)]
+extern "C" fn kernel_arm_oneshot(deadline: u64) {
+    arm_oneshot(deadline)
+}
+los_api::export_symbol!(kernel_arm_oneshot, 1);
@@ -2,7 +2,24 @@
 
 use los_api::println;
 
+los_api::module_info! {
+    name: "hello_world",
+    version: env!("CARGO_PKG_VERSION"),
+    license: "MIT OR Apache-2.0",
+}
+
+los_api::module_param! {
+    /// Who to greet, set with `greeting=...` in the module string
+    static GREETING: &str = "world";
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn module_init() -> i32 {
+    println!("Hello, {}! From kernel module!", GREETING.get());
+    0
+}
+
 #[unsafe(no_mangle)]
-extern "C" fn module_init() {
-    println!("Hello, world! From kernel module!");
+extern "C" fn module_fini() {
+    println!("Goodbye from kernel module!");
 }
@@ -1,15 +1,35 @@
-use core::ffi::c_char;
+use core::ffi::{CStr, c_char};
 
-use los_api::{auxv::AuxvEnt, hcf};
+use los_api::{auxv::AuxvEnt, cmdline::CommandLine, hcf, println};
 
 #[cfg(target_arch = "x86_64")]
 mod x86;
 
+los_api::cmdline_options! {
+    static KERNEL_OPTIONS = [
+        OptionSpec::str("mount-boot", "Where to mount the partition the system was booted from"),
+        OptionSpec::str("init", "Path of the first program to run"),
+    ];
+}
+
+/// The arguments after `argv[0]`, which is the kernel's file name.
+unsafe fn args<'a>(argc: isize, argv: *mut *mut c_char) -> impl Iterator<Item = &'a str> + Clone {
+    let argv = unsafe { core::slice::from_raw_parts(argv, argc as usize) };
+    argv.iter()
+        .skip(1)
+        .map(|&arg| unsafe { CStr::from_ptr(arg) }.to_str().unwrap_or(""))
+}
+
 unsafe extern "C" fn begin_kernel(
     argc: isize,
     argv: *mut *mut c_char,
     envp: *mut *mut c_char,
     auxv: *mut AuxvEnt,
 ) -> ! {
+    // The loader has already warned about options nothing declares
+    let options = CommandLine::from_args(unsafe { args(argc, argv) }, &KERNEL_OPTIONS);
+    let mount_boot = options.string("mount-boot").unwrap_or("/boot");
+    let init = options.string("init").unwrap_or("/sbin/init");
+    println!("Mounting the boot partition at {mount_boot}, then starting {init}");
     hcf()
 }
@@ -1,5 +1,11 @@
 #![no_std]
 
+los_api::module_info! {
+    name: "lilium-kernel",
+    version: env!("CARGO_PKG_VERSION"),
+    license: "MIT OR Apache-2.0",
+}
+
 mod entry;
 
 mod random;
@@ -0,0 +1,24 @@
+#!/bin/sh
+# Appends a module signature to a kernel module: an Ed25519 signature over the 64-byte SHAKE256
+# digest of the file, followed by a 16-byte magic trailer.
+#
+# Generate a key pair with:
+#   openssl genpkey -algorithm ed25519 -out keys/module-signing.pem
+#   openssl pkey -in keys/module-signing.pem -pubout -outform DER | tail -c 32 > keys/module-signing.pub
+# and rebuild the loader to embed the public key.
+
+if [ $# -ne 2 ]; then
+    echo "usage: $0 <private key> <module>" >&2
+    exit 1
+fi
+
+key="$1"
+module="$2"
+digest=$(mktemp) || exit 1
+signature=$(mktemp) || exit 1
+trap 'rm -f "$digest" "$signature"' EXIT
+
+openssl dgst -shake256 -xoflen 64 -binary "$module" > "$digest" || exit 1
+openssl pkeyutl -sign -rawin -inkey "$key" -in "$digest" -out "$signature" || exit 1
+cat "$signature" >> "$module"
+printf 'LILIUM MODSIG 1\n' >> "$module"